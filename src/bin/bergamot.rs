@@ -1,232 +1,1218 @@
 use bergamot::{
-    create_output_windows, error::Error, get_connection, get_rectangles, get_screen, Area, Colour,
-    Command, Output, Config, Cursors, Draw, Layout, Paint, Update, Widget,
+    create_image_output, create_output_windows, error::Error, get_connection, get_rectangles,
+    get_screen, i3bar, lemonbar, update_input_shapes, update_output_windows, watch_monitors, Add,
+    ClickHandler, ClickOutput, Command, Config, Damage, Draw, Height, MouseButton, Outputs, Paint,
+    Position, Rectangle, Remove, Update, UpdateArea, Widget, XcbConnection,
 };
-use std::sync::{mpsc::channel, Arc, Mutex};
-
-fn display(windows: &[Output], widgets: &[Widget]) -> Vec<Paint> {
-    let mut area_paints = vec![];
-
-    for (output_no, output) in windows.iter().enumerate() {
-        let (centered, mut uncentered): (Vec<(&Widget, &Area, Layout)>, _) =
-            widgets
-            .iter()
-            .flat_map(|w| {
-                w.content
-                    .iter()
-                    .map(move |a| (w, a, Layout::new(&output.ctx, a, &output.font.0)))
-            })
-            .partition(|(w, _, _)| w.alignment.is_center());
-	
-	let (right, left): (Vec<(&Widget, &Area, Layout)>, _) = uncentered
-	    .drain(..)
-	    .partition(|(w, _, _)| w.alignment.is_right());
-	
-        let center_width: f64 = centered.iter().map(|(_, _, l)| l.width).sum();
-	let right_width: f64 = right.iter().map(|(_, _, l)| l.width).sum();
-
-        let mut cursors = Cursors {
-            top: 0.0,
-            bottom: output.cfg.height as f64,
-            left: 0.0,
-            center: (output.rect.width / 2.0) - (center_width / 2.0),
-            right: output.rect.width - right_width,
+use log::{debug, error, warn};
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use xcb::Xid;
+
+/// Messages for the draw thread.
+enum Message {
+    Redraw(Damage),
+    /// Re-read the config file, then redraw.
+    Reload,
+    /// The X connection is gone; stop drawing.
+    Shutdown,
+}
+
+/// Lays out and paints every bar. See [`bergamot::Output::render`].
+fn display(
+    windows: &Outputs,
+    widgets: &[Widget],
+    previous: &[Paint],
+    damage: &Damage,
+) -> Vec<Paint> {
+    windows
+        .iter()
+        .flat_map(|output| output.render(widgets, previous, damage))
+        .collect()
+}
+
+/// A command as read from stdin, with the `id` its sender wants acknowledged, if any.
+struct Request {
+    id: Option<serde_json::Value>,
+    command: Result<Command, serde_json::Error>,
+}
+
+impl Request {
+    fn new(mut value: serde_json::Value) -> Self {
+        let id = value.as_object_mut().and_then(|o| o.remove("id"));
+        Self {
+            id,
+            command: serde_json::from_value(value),
+        }
+    }
+
+    /// Writes `{"id":..,"ok":..}` to `reply`, if the sender asked for it.
+    fn acknowledge(
+        id: Option<serde_json::Value>,
+        result: &Result<Damage, String>,
+        reply: &mut dyn Write,
+    ) {
+        if let Some(id) = id {
+            let ack = match result {
+                Ok(_) => serde_json::json!({ "id": id, "ok": true }),
+                Err(e) => serde_json::json!({ "id": id, "ok": false, "error": e }),
+            };
+            // The sender may have hung up already; nothing is lost by not telling it.
+            let _ = writeln!(reply, "{}", ack);
+        }
+    }
+}
+
+/// A line is either a single command or an array of them.
+fn parse_commands(line: &str) -> Result<Vec<Request>, serde_json::Error> {
+    match serde_json::from_str(line)? {
+        serde_json::Value::Array(values) => Ok(values.into_iter().map(Request::new).collect()),
+        value => Ok(vec![Request::new(value)]),
+    }
+}
+
+/// Applies a command to the widget list, returning what of the bars needs redrawing. Query
+/// results are written to `reply`.
+fn apply_command(
+    command: Command,
+    outputs: &Outputs,
+    widgets: &mut Vec<Widget>,
+    reply: &mut dyn Write,
+) -> Result<Damage, String> {
+    match command {
+        Command::Update(Update { tag, content }) => {
+            if tag.is_empty() {
+                return Err("Cannot update an untagged widget".to_string());
+            }
+            let widget = widgets
+                .iter_mut()
+                .find(|w| w.tag == tag)
+                .ok_or_else(|| format!("No such widget '{}'", tag))?;
+            widget.content = content;
+            Ok(Damage::Tags(vec![tag]))
+        }
+        Command::UpdateArea(UpdateArea { tag, index, area }) => {
+            if tag.is_empty() {
+                return Err("Cannot update an untagged widget".to_string());
+            }
+            let widget = widgets
+                .iter_mut()
+                .find(|w| w.tag == tag)
+                .ok_or_else(|| format!("No such widget '{}'", tag))?;
+            let len = widget.content.len();
+            let slot = widget
+                .content
+                .get_mut(index)
+                .ok_or_else(|| format!("Widget '{}' has no area {} (it has {})", tag, index, len))?;
+            *slot = area;
+            Ok(Damage::Tags(vec![tag]))
+        }
+        Command::Draw(Draw {
+            widgets: new_widgets,
+            strict,
+        }) => {
+            // Updates go to the first widget with a tag, so a repeated tag hides the others.
+            let mut seen = HashSet::new();
+            let mut duplicates: Vec<&str> = new_widgets
+                .iter()
+                .map(|w| w.tag.as_str())
+                .filter(|tag| !tag.is_empty() && !seen.insert(*tag))
+                .collect();
+            duplicates.sort_unstable();
+            duplicates.dedup();
+            if !duplicates.is_empty() {
+                let msg = format!("Draw has duplicate widget tags: {}", duplicates.join(", "));
+                if strict {
+                    return Err(msg);
+                }
+                warn!("{}", msg);
+            }
+
+            *widgets = new_widgets;
+            Ok(Damage::All)
+        }
+        Command::Add(Add { widget }) => {
+            let existing = widgets
+                .iter_mut()
+                .find(|w| !widget.tag.is_empty() && w.tag == widget.tag);
+            if let Some(existing) = existing {
+                *existing = widget;
+            } else {
+                widgets.push(widget);
+            }
+            Ok(Damage::All)
+        }
+        Command::Remove(Remove { tag }) => {
+            if tag.is_empty() {
+                return Err("Cannot remove an untagged widget".to_string());
+            }
+            let before = widgets.len();
+            widgets.retain(|w| w.tag != tag);
+            if widgets.len() == before {
+                return Err(format!("No such widget '{}'", tag));
+            }
+            Ok(Damage::All)
+        }
+        Command::Query => {
+            let monitors: Vec<_> = outputs
+                .iter()
+                .map(|o| {
+                    serde_json::json!({
+                        "name": o.name,
+                        "primary": o.primary,
+                        "rect": o.rect,
+                    })
+                })
+                .collect();
+            let state = serde_json::json!({
+                "widgets": widgets,
+                "monitors": monitors,
+            });
+            let _ = writeln!(reply, "{}", state);
+            Ok(Damage::None)
+        }
+        Command::Redraw => Ok(Damage::All),
+    }
+}
+
+/// Applies every command on an input line, acknowledging each to `reply`, and returns the
+/// combined damage.
+fn apply_line(
+    line: &str,
+    outputs: &Outputs,
+    widgets: &mut Vec<Widget>,
+    reply: &mut dyn Write,
+) -> Damage {
+    let commands = match parse_commands(line) {
+        Ok(commands) => commands,
+        Err(e) => {
+            warn!("Failed to read command at line <{}>\nError: {}", line, e);
+            return Damage::None;
+        }
+    };
+
+    let mut damage = Damage::None;
+    for Request { id, command } in commands {
+        let result = match command {
+            Ok(command) => {
+                debug!("Applying {:?}", command);
+                apply_command(command, outputs, widgets, reply)
+            }
+            Err(e) => Err(format!("Failed to read command at line <{}>: {}", line, e)),
         };
+        Request::acknowledge(id, &result, reply);
+        match result {
+            Ok(d) => damage = damage.merge(d),
+            Err(e) => warn!("{}", e),
+        }
+    }
+    damage
+}
 
-        output.ctx.set_colour(&output.cfg.default_bg);
-        output.ctx.rectangle(&cursors.as_rectangle());
-        output.ctx.fill();
+/// Applies a line of input in `format`. A status line in one of the foreign formats replaces
+/// every widget.
+fn apply_input(
+    format: InputFormat,
+    line: &str,
+    outputs: &Outputs,
+    widgets: &mut Vec<Widget>,
+    reply: &mut dyn Write,
+) -> Damage {
+    let status = match format {
+        InputFormat::Json => return apply_line(line, outputs, widgets, reply),
+        InputFormat::I3bar => i3bar::parse_line(line),
+        InputFormat::Lemonbar => Ok(Some(lemonbar::parse_line(line))),
+    };
+    match status {
+        Ok(Some(status)) => {
+            *widgets = status;
+            Damage::All
+        }
+        Ok(None) => Damage::None,
+        Err(e) => {
+            warn!("Failed to read {} status line <{}>\nError: {}", format, line, e);
+            Damage::None
+        }
+    }
+}
 
-        for (widget, area, layout) in left.iter().chain(right.iter()).chain(centered.iter()) {
-            let monitor_constaints: Vec<_> = widget.constraints.monitor().collect();
+/// A line of input that cannot be applied.
+enum BadLine {
+    /// Longer than the `max_len` the lines are read with.
+    TooLong,
+    NotUtf8(std::string::FromUtf8Error),
+}
 
-            if !monitor_constaints.is_empty()
-                && !monitor_constaints.iter().any(|m| m.number() == output_no)
-            {
-                continue;
+/// The lines of a command stream. As an iterator, lines that are not UTF-8 or are longer than
+/// `max_len` bytes are skipped with a warning; an overlong line is discarded as it arrives, not
+/// buffered whole.
+struct InputLines<R> {
+    input: R,
+    max_len: usize,
+}
+
+impl<R: BufRead> InputLines<R> {
+    fn new(input: R, max_len: usize) -> Self {
+        Self { input, max_len }
+    }
+
+    /// Discards the input up to and including the next newline.
+    fn skip_line(&mut self) -> std::io::Result<()> {
+        loop {
+            let buf = self.input.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(());
+            }
+            match buf.iter().position(|&b| b == b'\n') {
+                Some(i) => {
+                    self.input.consume(i + 1);
+                    return Ok(());
+                }
+                None => {
+                    let len = buf.len();
+                    self.input.consume(len);
+                }
+            }
+        }
+    }
+
+    /// Reads the next line, or the reason it cannot be used. Returns `None` once the input closes.
+    fn read_line(&mut self) -> std::io::Result<Option<Result<String, BadLine>>> {
+        let mut buf = Vec::new();
+        let mut input = std::io::Read::take(&mut self.input, self.max_len as u64 + 1);
+        if input.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(None);
+        }
+
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+            if buf.last() == Some(&b'\r') {
+                buf.pop();
+            }
+        } else if buf.len() > self.max_len {
+            self.skip_line()?;
+            return Ok(Some(Err(BadLine::TooLong)));
+        }
+
+        Ok(Some(String::from_utf8(buf).map_err(BadLine::NotUtf8)))
+    }
+}
+
+impl<R: BufRead> Iterator for InputLines<R> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        loop {
+            match self.read_line() {
+                Ok(Some(Ok(line))) => return Some(line),
+                Ok(Some(Err(BadLine::TooLong))) => {
+                    warn!("Skipping a line longer than {} bytes", self.max_len)
+                }
+                Ok(Some(Err(BadLine::NotUtf8(e)))) => warn!(
+                    "Skipping a line that is not valid UTF-8 <{}>",
+                    String::from_utf8_lossy(e.as_bytes())
+                ),
+                Ok(None) => return None,
+                Err(e) => {
+                    warn!("Failed to read input: {}", e);
+                    return None;
+                }
             }
+        }
+    }
+}
 
-            let bg = area.colours.bg.unwrap_or(output.cfg.default_bg);
-            let fg = area.colours.fg.unwrap_or(output.cfg.default_fg);
+/// Applies lines of `format` from `input` until it closes, asking the draw thread to redraw after
+/// each line that changed anything.
+fn read_commands(
+    format: InputFormat,
+    input: impl Iterator<Item = String>,
+    mut reply: impl Write,
+    outputs: &Mutex<Outputs>,
+    widgets: &Mutex<Vec<Widget>>,
+    tx: &Sender<Message>,
+) {
+    for line in input {
+        // A batch applies under a single lock and redraws at most once.
+        let outputs = outputs.lock().unwrap();
+        let mut widgets = widgets.lock().unwrap();
+        let damage = apply_input(format, &line, &outputs, &mut widgets, &mut reply);
+        if !damage.is_none() {
+            tx.send(Message::Redraw(damage)).unwrap();
+        }
+    }
+}
 
-            output.ctx.set_colour(&bg);
+/// Parses every line of `input` as `format` without applying it, printing each failure with its
+/// line number to stderr. Returns how many lines failed.
+fn check_input(
+    format: InputFormat,
+    input: impl BufRead,
+    max_line: usize,
+) -> Result<usize, Error> {
+    let mut lines = InputLines::new(input, max_line);
+    let mut failures = 0;
+    for number in 1.. {
+        let line = match lines.read_line()? {
+            Some(line) => line,
+            None => break,
+        };
 
-            let rect = cursors.make_bounding_rectangle(widget, layout);
+        let error = match line {
+            Err(BadLine::TooLong) => Some(format!("longer than {} bytes", max_line)),
+            Err(BadLine::NotUtf8(e)) => Some(format!("not valid UTF-8: {}", e.utf8_error())),
+            Ok(line) => match format {
+                InputFormat::Json => match parse_commands(&line) {
+                    Ok(requests) => requests
+                        .into_iter()
+                        .find_map(|r| r.command.err())
+                        .map(|e| e.to_string()),
+                    Err(e) => Some(e.to_string()),
+                },
+                InputFormat::I3bar => i3bar::parse_line(&line).err().map(|e| e.to_string()),
+                InputFormat::Lemonbar => None,
+            },
+        };
+        if let Some(e) = error {
+            eprintln!("line {}: {}", number, e);
+            failures += 1;
+        }
+    }
+    Ok(failures)
+}
 
-            output.ctx.rectangle(&rect);
-            output.ctx.fill();
+/// Makes a FIFO at `path`, unless there is one already.
+fn create_fifo(path: &Path) -> Result<(), Error> {
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if metadata.file_type().is_fifo() {
+            return Ok(());
+        }
+        return Err(Error::Config(format!("{} exists and is not a FIFO", path.display())));
+    }
 
-	    output.ctx.status();
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::Config(format!("Invalid FIFO path {}", path.display())))?;
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
 
-            output.ctx.set_colour(&fg);
+/// Binds the command socket at `path`, replacing a stale socket left by an earlier run.
+fn bind_socket(path: &Path) -> Result<UnixListener, Error> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::Config(format!(
+                "Another bergamot is listening on {}",
+                path.display()
+            )));
+        }
+        std::fs::remove_file(path)?;
+    }
+    Ok(UnixListener::bind(path)?)
+}
 
-            output
-                .ctx
-                .move_to(rect.x + 5.0, rect.height / 2.0 - layout.height / 2.0);
+/// Where each tagged widget ended up on the screen, for `--emit-layout`. A widget's areas on
+/// one bar are merged into a single rectangle.
+fn layout_event(outputs: &Outputs, paints: &[Paint]) -> serde_json::Value {
+    let mut extents: Vec<(&Paint, f64, f64)> = Vec::new();
+    for p in paints.iter().filter(|p| !p.tag.is_empty()) {
+        match extents
+            .iter_mut()
+            .find(|(q, ..)| q.win == p.win && q.tag == p.tag)
+        {
+            Some((_, left, right)) => {
+                *left = left.min(p.left);
+                *right = right.max(p.right);
+            }
+            None => extents.push((p, p.left, p.right)),
+        }
+    }
 
-            layout.display(&output.ctx);
+    let widgets: Vec<_> = extents
+        .into_iter()
+        .filter_map(|(p, left, right)| {
+            let output = outputs.by_window(p.win)?;
+            let border = f64::from(output.cfg.border_width);
+            let (x, y) = (output.frame.x + border, output.frame.y + border);
+            let thickness = f64::from(output.height);
+            let rect = if output.cfg.position.is_vertical() {
+                Rectangle::new(x, y + left, thickness, right - left)
+            } else {
+                Rectangle::new(x + left, y, right - left, thickness)
+            };
+            Some(serde_json::json!({
+                "tag": p.tag,
+                "monitor": p.monitor,
+                "win": p.win.resource_id(),
+                "rect": rect,
+            }))
+        })
+        .collect();
+    serde_json::json!({ "event": "layout", "widgets": widgets })
+}
 
-	    output.ctx.status();
+/// How far along its bar a pointer at (`x`, `y`) in `win` is: across for most bars, down for
+/// vertical ones.
+fn along_bar(outputs: &Outputs, win: xcb::x::Window, x: i16, y: i16) -> f64 {
+    match outputs.by_window(win) {
+        Some(output) if output.cfg.position.is_vertical() => y.into(),
+        _ => x.into(),
+    }
+}
 
-            area_paints.push(Paint {
-                left: rect.x,
-                right: rect.x + rect.width,
-                win: output.win,
-                area: (*area).clone(),
-            });
+/// The smallest painted area under `x` in `win`, counting each area's click padding as part of
+/// it. Areas are still compared by their drawn width.
+fn paint_at(paints: &[Paint], win: xcb::x::Window, x: f64) -> Option<&Paint> {
+    paints
+        .iter()
+        .filter(|p| p.win == win && p.left - p.click_padding <= x && p.right + p.click_padding >= x)
+        .min_by(|p1, p2| {
+            (p1.right - p1.left)
+                .partial_cmp(&(p2.right - p2.left))
+                .unwrap()
+        })
+}
+
+/// The area the pointer is over, remembered so its leave outputs fire even after a redraw.
+struct Hover {
+    win: xcb::x::Window,
+    left: f64,
+    tag: String,
+    on_leave: Vec<String>,
+}
+
+impl Hover {
+    fn leave(self, events_json: bool) {
+        emit_hover(events_json, &self.tag, "leave", &self.on_leave);
+    }
+}
+
+/// Prints a click handler's output, or with `exec`, runs it as a shell command.
+fn emit_click(exec: bool, line: &str) {
+    if !exec {
+        println!("{}", line);
+        return;
+    }
+
+    // In its own process group, so a ^C meant for bergamot doesn't reach handlers; stdout is
+    // left for bergamot's own output.
+    let child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(line)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .process_group(0)
+        .spawn();
+    match child {
+        // Reap the child when it exits, so it doesn't linger as a zombie.
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
         }
+        Err(e) => error!("Failed to run click handler <{}>\nError: {}", line, e),
     }
-    
-    area_paints
-}
-
-fn main() -> Result<(), Error> {
-    use std::str::FromStr;
-    
-    let cfgs = vec![
-	Config {
-            height: 14,
-            font_str: "Iosevka Term 9".to_string(),
-            default_bg: Colour::from_str("#333232").unwrap(),
-            default_fg: Colour::from_str("#a7a5a5").unwrap()
-	},
-	Config {
-            height: 18,
-            font_str: "Iosevka Term 12".to_string(),
-            default_bg: Colour::from_str("#333232").unwrap(),
-            default_fg: Colour::from_str("#a7a5a5").unwrap()
-	},
-	Config {
-            height: 18,
-            font_str: "Iosevka Term 12".to_string(),
-            default_bg: Colour::from_str("#333232").unwrap(),
-            default_fg: Colour::from_str("#a7a5a5").unwrap()
-	},
-    ];
-	
-    let conn = get_connection()?;
-    let screen = get_screen(&conn);
-    let rectangles = get_rectangles(&conn, &screen)?;
-    let windows = create_output_windows(&conn, &screen, &cfgs, rectangles);
+}
+
+fn emit_hover(events_json: bool, tag: &str, event: &str, outputs: &[String]) {
+    for output in outputs {
+        if events_json {
+            let event = serde_json::json!({ "tag": tag, "event": event, "output": output });
+            println!("{}", event);
+        } else {
+            println!("{}", output);
+        }
+    }
+}
 
+/// A click on an area with double click handlers, waiting to see whether a second one follows.
+struct PendingClick {
+    win: xcb::x::Window,
+    left: f64,
+    button: MouseButton,
+    time: xcb::x::Timestamp,
+    generation: u64,
+}
+
+/// The name a button goes by in the JSON protocol, e.g. "scrollUp".
+fn button_name(button: &MouseButton) -> String {
+    match serde_json::to_value(button) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => format!("{:?}", button),
+    }
+}
+
+/// Substitutes each placeholder in `template`, leaving any it doesn't know untouched.
+fn expand_placeholders(template: &str, placeholders: &[(&str, String)]) -> String {
+    placeholders
+        .iter()
+        .fold(template.to_string(), |output, (placeholder, value)| {
+            output.replace(placeholder, value)
+        })
+}
+
+fn builtin_configs() -> Result<Vec<Config>, Error> {
+    let bar = |height: u32, font: &str| {
+        Config::builder()
+            .height(Height::Fixed(height))
+            .font(font)
+            .default_bg("#333232")
+            .default_fg("#a7a5a5")
+            .build()
+    };
+
+    Ok(vec![
+        bar(14, "Iosevka Term 9")?,
+        bar(18, "Iosevka Term 12")?,
+        bar(18, "Iosevka Term 12")?,
+    ])
+}
+
+const USAGE: &str = "\
+Usage: bergamot [OPTIONS]
+
+Reads newline-delimited JSON commands on stdin and draws them on a bar per monitor.
+Send SIGHUP to reload colours, fonts and padding from the config file.
+Emoji are drawn in colour if fontconfig can find a colour emoji font, such as Noto Color Emoji.
+COLRv1 fonts need cairo 1.18 or newer.
+
+Options:
+    --config <path>   Read bar configuration from <path>
+    --height <px>     Override the height of every bar, or \"auto\" to fit the font
+    --font <str>      Override the font of every bar
+    --bottom          Place the bars at the bottom of their monitors
+    --events-json     Report clicks on stdout as JSON objects instead of bare handler output
+    --exec-clicks     Run click handler output with `sh -c` instead of printing it
+    --emit-layout     After each redraw, print where every tagged widget is on the screen as a
+                      JSON object
+    --debounce <ms>   Wait this long for more commands before redrawing (default 16)
+    --max-line <bytes>
+                      Skip input lines longer than this (default 1048576)
+    --input <format>  Read stdin as \"json\" commands (the default), as an \"i3bar\" protocol
+                      stream, as written by i3status and i3blocks, or as \"lemonbar\"
+                      format strings
+    --socket <path>   Also accept commands from clients connecting to a Unix socket at <path>
+    --fifo <path>     Also read commands from a FIFO at <path>, creating it if need be. Writers
+                      may come and go; query results are not sent back
+    --render-png <path>
+                      Instead of opening windows, apply the commands on stdin until it closes
+                      and save the resulting bar to <path>
+    --width <px>      The width of the bar drawn by --render-png (default 1280). Its height
+                      comes from the first bar config, or from --height if given
+    --check           Parse the config and every line on stdin, report what fails, and exit
+                      without connecting to X. Exits with status 1 if anything failed
+    --help            Print this message
+";
+
+/// The format of the commands on stdin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InputFormat {
+    Json,
+    I3bar,
+    Lemonbar,
+}
+
+impl std::str::FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "json" => Ok(Self::Json),
+            "i3bar" => Ok(Self::I3bar),
+            "lemonbar" => Ok(Self::Lemonbar),
+            _ => Err(format!("Unknown input format '{}'", value)),
+        }
+    }
+}
+
+impl std::fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Json => "json",
+            Self::I3bar => "i3bar",
+            Self::Lemonbar => "lemonbar",
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Args {
+    config: Option<PathBuf>,
+    height: Option<Height>,
+    font: Option<String>,
+    bottom: bool,
+    events_json: bool,
+    exec_clicks: bool,
+    emit_layout: bool,
+    debounce_ms: u64,
+    max_line: usize,
+    input: InputFormat,
+    socket: Option<PathBuf>,
+    fifo: Option<PathBuf>,
+    render_png: Option<PathBuf>,
+    width: u32,
+    check: bool,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            config: None,
+            height: None,
+            font: None,
+            bottom: false,
+            events_json: false,
+            exec_clicks: false,
+            emit_layout: false,
+            debounce_ms: 16,
+            max_line: 1 << 20,
+            input: InputFormat::Json,
+            socket: None,
+            fifo: None,
+            render_png: None,
+            width: 1280,
+            check: false,
+        }
+    }
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut parsed = Args::default();
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            // Values may also be given as `--name=value`.
+            let (arg, mut inline) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => {
+                    (name.to_string(), Some(value.to_string()))
+                }
+                _ => (arg, None),
+            };
+            let mut value = |name: &str| {
+                inline
+                    .take()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{} requires a value", name))
+            };
+
+            match arg.as_str() {
+                "--config" => parsed.config = Some(PathBuf::from(value("--config")?)),
+                "--height" => {
+                    let height = value("--height")?;
+                    parsed.height = Some(height.parse().map_err(|e| format!("{:?}", e))?);
+                }
+                "--font" => parsed.font = Some(value("--font")?),
+                "--bottom" => parsed.bottom = true,
+                "--events-json" => parsed.events_json = true,
+                "--exec-clicks" => parsed.exec_clicks = true,
+                "--emit-layout" => parsed.emit_layout = true,
+                "--debounce" => {
+                    let ms = value("--debounce")?;
+                    parsed.debounce_ms =
+                        ms.parse().map_err(|_| format!("Invalid --debounce '{}'", ms))?;
+                }
+                "--max-line" => {
+                    let bytes = value("--max-line")?;
+                    parsed.max_line = bytes
+                        .parse()
+                        .map_err(|_| format!("Invalid --max-line '{}'", bytes))?;
+                }
+                "--input" => parsed.input = value("--input")?.parse()?,
+                "--socket" => parsed.socket = Some(PathBuf::from(value("--socket")?)),
+                "--fifo" => parsed.fifo = Some(PathBuf::from(value("--fifo")?)),
+                "--render-png" => parsed.render_png = Some(PathBuf::from(value("--render-png")?)),
+                "--width" => {
+                    let width = value("--width")?;
+                    parsed.width = width
+                        .parse()
+                        .map_err(|_| format!("Invalid --width '{}'", width))?;
+                }
+                "--check" => parsed.check = true,
+                "--help" => {
+                    print!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                _ => return Err(format!("Unknown argument '{}'", arg)),
+            }
+        }
+
+        if parsed.events_json && parsed.exec_clicks {
+            return Err("--events-json and --exec-clicks cannot be used together".to_string());
+        }
+        Ok(parsed)
+    }
+
+    /// Applies the command-line overrides on top of the loaded configs.
+    fn apply(&self, cfgs: &mut [Config]) {
+        for cfg in cfgs {
+            if let Some(height) = self.height {
+                cfg.height = height;
+            }
+            if let Some(font) = &self.font {
+                cfg.font_str = font.clone();
+            }
+            if self.bottom {
+                cfg.position = Position::Bottom;
+            }
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/bergamot/config.json`, defaulting `$XDG_CONFIG_HOME` to `~/.config`.
+fn default_config_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("bergamot").join("config.json"))
+}
+
+fn load_configs(args: &Args) -> Result<Vec<Config>, Error> {
+    if let Some(path) = &args.config {
+        return Config::load(path);
+    }
+
+    match default_config_path() {
+        Some(path) if path.exists() => Config::load(path),
+        _ => builtin_configs(),
+    }
+}
+
+/// Rebuilds the bar windows for the current monitor layout, leaving the widgets alone.
+fn relayout(
+    conn: &XcbConnection,
+    outputs: &Mutex<Outputs>,
+    configs: &Mutex<Vec<Config>>,
+) -> Result<(), Error> {
+    let screen = get_screen(conn)?;
+    let monitors = get_rectangles(conn, screen)?;
+
+    let mut outputs = outputs.lock().unwrap();
+    let configs = configs.lock().unwrap();
+    let existing = std::mem::take(&mut *outputs);
+    *outputs = update_output_windows(conn, screen, &configs, monitors, existing)?;
+    conn.flush();
+    Ok(())
+}
+
+/// Draws the first config's bar into a PNG once stdin closes, without an X server.
+fn render_png(
+    path: &Path,
+    width: u32,
+    format: InputFormat,
+    max_line: usize,
+    cfgs: &[Config],
+) -> Result<(), Error> {
+    let cfg = cfgs
+        .first()
+        .ok_or_else(|| Error::Config("No bar config to render".into()))?;
+    let outputs = Outputs::from(vec![create_image_output(cfg, width)?]);
+    let mut widgets = Vec::new();
+
+    let mut stdout = std::io::stdout();
+    for line in InputLines::new(std::io::stdin().lock(), max_line) {
+        apply_input(format, &line, &outputs, &mut widgets, &mut stdout);
+    }
+
+    display(&outputs, &widgets, &[], &Damage::All);
+    let output = outputs.get(0).expect("the image output");
+    output.ctx.write_png(&mut std::fs::File::create(path)?)
+}
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    if let Err(e) = run() {
+        // Not logged, so that RUST_LOG=off cannot hide why bergamot exited.
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Error> {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let mut cfgs = load_configs(&args)?;
+    args.apply(&mut cfgs);
+
+    if args.check {
+        if check_input(args.input, std::io::stdin().lock(), args.max_line)? > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(path) = &args.render_png {
+        return render_png(path, args.width, args.input, args.max_line, &cfgs);
+    }
+
+    let conn = get_connection()?;
+    let screen = get_screen(&conn)?;
+    // Watch before looking, so a monitor connected in between isn't missed.
+    watch_monitors(&conn, screen);
+    let monitors = get_rectangles(&conn, screen)?;
+    let outputs = create_output_windows(&conn, screen, &cfgs, monitors)?;
 
     conn.0.flush().expect("Failed to flush connection");
 
     let (tx, rx) = channel();
 
-    let conn = Arc::new(conn);
+    let outputs = Arc::new(Mutex::new(outputs));
+    let configs = Arc::new(Mutex::new(cfgs));
     let paints = Arc::new(Mutex::new(Vec::new()));
 
     let widgets: Arc<Mutex<Vec<Widget>>> = Arc::new(Mutex::new(Vec::new()));
 
     let _stdin_handle = {
+        let outputs = Arc::clone(&outputs);
         let widgets = Arc::clone(&widgets);
         let tx = tx.clone();
+        let format = args.input;
+        let max_line = args.max_line;
         std::thread::spawn(move || {
-            use std::io::BufRead;
-
-            let stdin = std::io::stdin();
-            let stdin = stdin.lock();
-
-            for line in stdin.lines() {
-                if let Ok(line) = line {
-                    match serde_json::from_str(&line) {
-			Ok(command) => 
-                            match command {
-				Command::Update(Update { tag, content }) => {
-                                    if tag == "" {
-					eprintln!("Cannot update an untagged widget");
-					continue;
-                                    }
-                                    let mut widgets = widgets.lock().unwrap();
-                                    let widget = widgets.iter_mut().find(|w| w.tag == tag);
-                                    if let Some(mut widget) = widget {
-					widget.content = content;
-					tx.send(()).unwrap();
-                                    } else {
-					eprintln!("No such widget '{}'", tag);
-                                    }
-				}
-				Command::Draw(Draw {
-                                    widgets: new_widgets,
-				}) => {
-                                    let mut widgets = widgets.lock().unwrap();
-                                    widgets.clear();
-                                    *widgets = new_widgets;
-                                    tx.send(()).unwrap();
-				}
-                            },
-			Err(e) => {
-			    eprintln!("Failed to read command at line <{}>\nError: {}", line, e);
-			}
-		    }
+            let stdin = InputLines::new(std::io::stdin().lock(), max_line);
+            read_commands(format, stdin, std::io::stdout(), &outputs, &widgets, &tx);
+        })
+    };
+
+    if let Some(path) = &args.socket {
+        let listener = bind_socket(path)?;
+        let max_line = args.max_line;
+        let outputs = Arc::clone(&outputs);
+        let widgets = Arc::clone(&widgets);
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("Failed to accept socket connection: {}", e);
+                        continue;
+                    }
+                };
+                let reply = match stream.try_clone() {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        error!("Failed to set up socket connection: {}", e);
+                        continue;
+                    }
+                };
+                let outputs = Arc::clone(&outputs);
+                let widgets = Arc::clone(&widgets);
+                let tx = tx.clone();
+                std::thread::spawn(move || {
+                    let input = InputLines::new(BufReader::new(stream), max_line);
+                    read_commands(InputFormat::Json, input, reply, &outputs, &widgets, &tx)
+                });
+            }
+        });
+    }
+
+    if let Some(path) = &args.fifo {
+        create_fifo(path)?;
+        let path = path.clone();
+        let max_line = args.max_line;
+        let outputs = Arc::clone(&outputs);
+        let widgets = Arc::clone(&widgets);
+        let tx = tx.clone();
+        // Opening blocks until a writer turns up, and reading ends when the last one closes, so
+        // reopen for the next.
+        std::thread::spawn(move || loop {
+            match std::fs::File::open(&path) {
+                Ok(fifo) => {
+                    let input = InputLines::new(BufReader::new(fifo), max_line);
+                    let reply = std::io::sink();
+                    read_commands(InputFormat::Json, input, reply, &outputs, &widgets, &tx)
+                }
+                Err(e) => {
+                    error!("Failed to open FIFO {}: {}", path.display(), e);
+                    return;
+                }
+            }
+        });
+    }
+
+    // Colours, fonts and padding apply live on SIGHUP; height, position and border need a restart.
+    let _signal_handle = {
+        let mut signals = Signals::new([SIGHUP])?;
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                if tx.send(Message::Reload).is_err() {
+                    break;
                 }
             }
         })
     };
 
-    let _draw_handle = {
-        let conn = Arc::clone(&conn);
+    let events_json = args.events_json;
+    let exec_clicks = args.exec_clicks;
+    let debounce = std::time::Duration::from_millis(args.debounce_ms);
+
+    // Lock order, where more than one is needed: outputs, configs, widgets, paints.
+    let draw_handle = {
+        let conn = conn.clone();
         let paints = Arc::clone(&paints);
+        let outputs = Arc::clone(&outputs);
+        let configs = Arc::clone(&configs);
 
         let widgets = Arc::clone(&widgets);
+        let emit_layout = args.emit_layout;
         std::thread::spawn(move || {
-            while let Ok(_) = rx.recv() {
+            while let Ok(message) = rx.recv() {
+                // Coalesce bursts of commands into one redraw.
+                let deadline = std::time::Instant::now() + debounce;
+                let mut messages = vec![message];
+                while let Ok(message) =
+                    rx.recv_timeout(deadline.saturating_duration_since(std::time::Instant::now()))
+                {
+                    messages.push(message);
+                }
+
+                let mut damage = Damage::None;
+                let mut reload = false;
+                for message in messages {
+                    match message {
+                        Message::Redraw(d) => damage = damage.merge(d),
+                        Message::Reload => {
+                            damage = Damage::All;
+                            reload = true;
+                        }
+                        Message::Shutdown => return,
+                    }
+                }
+
+                let mut outputs = outputs.lock().unwrap();
+
+                if reload {
+                    match load_configs(&args) {
+                        Ok(mut cfgs) => {
+                            args.apply(&mut cfgs);
+                            for output in outputs.iter_mut() {
+                                let cfg = cfgs.get(output.config_index).or(cfgs.last());
+                                if let Some(cfg) = cfg {
+                                    output.reconfigure(cfg.clone());
+                                }
+                            }
+                            *configs.lock().unwrap() = cfgs;
+                        }
+                        Err(Error::Config(msg)) => error!("Failed to reload config: {}", msg),
+                        Err(e) => error!("Failed to reload config: {}", e),
+                    }
+                }
+
+                debug!("Redrawing {:?}", damage);
                 let widgets = widgets.lock().unwrap();
-                let new_paints = display(&windows, &widgets);
-                conn.flush();
                 let mut paints = paints.lock().unwrap();
+                let new_paints = display(&outputs, &widgets, &paints, &damage);
+                update_input_shapes(&conn, &outputs, &new_paints);
+                conn.flush();
+                if emit_layout {
+                    println!("{}", layout_event(&outputs, &new_paints));
+                }
                 let _ = std::mem::replace(&mut *paints, new_paints);
             }
         })
     };
 
-    while let Ok(xcb::Event::X(event)) = conn.0.wait_for_event() {
+    // Consecutive scrolls in one direction over one area: (window, area left edge, button, count).
+    let mut scrolls: Option<(xcb::x::Window, f64, MouseButton, u32)> = None;
+    let pending_click: Arc<Mutex<Option<PendingClick>>> = Arc::new(Mutex::new(None));
+    let mut click_generation = 0;
+    let mut hovered: Option<Hover> = None;
+
+    loop {
+        let event = conn.0.flush().map_err(xcb::Error::Connection);
+        let event = match event.and_then(|_| conn.0.wait_for_event()) {
+            Ok(event) => event,
+            // A failed request, e.g. for a window that has just gone away, leaves the connection
+            // usable.
+            Err(xcb::Error::Protocol(e)) => {
+                warn!("X protocol error: {:?}", e);
+                continue;
+            }
+            Err(e @ xcb::Error::Connection(_)) => {
+                // The stdin and signal threads block on input and die with the process; the draw
+                // thread may be mid-frame, so let it finish before exiting.
+                error!("Lost connection to the X server");
+                let _ = tx.send(Message::Shutdown);
+                let _ = draw_handle.join();
+                return Err(e.into());
+            }
+        };
+
 	match event {
-	    xcb::x::Event::Expose(_) => {
-		tx.send(()).unwrap();
+	    xcb::Event::X(xcb::x::Event::Expose(_)) => {
+		tx.send(Message::Redraw(Damage::All)).unwrap();
+	    },
+	    xcb::Event::RandR(xcb::randr::Event::ScreenChangeNotify(_)) => {
+                if let Err(e) = relayout(&conn, &outputs, &configs) {
+                    error!("Failed to update monitor layout: {}", e);
+                }
+                tx.send(Message::Redraw(Damage::All)).unwrap();
 	    },
-	    xcb::x::Event::ButtonPress(evt) => {
+	    xcb::Event::X(xcb::x::Event::MotionNotify(evt)) => {
                 let win = evt.event();
-                let x = evt.event_x().into();
-
+                let x = along_bar(&outputs.lock().unwrap(), win, evt.event_x(), evt.event_y());
                 let paints = paints.lock().unwrap();
+                let paint = paint_at(&paints, win, x);
+
+                let same = match (&hovered, paint) {
+                    (Some(h), Some(p)) => h.win == win && h.left == p.left,
+                    (None, None) => true,
+                    _ => false,
+                };
+
+                if !same {
+                    if let Some(h) = hovered.take() {
+                        h.leave(events_json);
+                    }
+                    if let Some(p) = paint {
+                        let h = Hover {
+                            win,
+                            left: p.left,
+                            tag: p.tag.clone(),
+                            on_leave: p.area.on_leave.clone(),
+                        };
+                        emit_hover(events_json, &p.tag, "enter", &p.area.on_enter);
+                        hovered = Some(h);
+                    }
+                }
+	    },
+	    xcb::Event::X(xcb::x::Event::LeaveNotify(evt)) => {
+                if let Some(h) = hovered.take_if(|h| h.win == evt.event()) {
+                    h.leave(events_json);
+                }
+	    },
+	    xcb::Event::X(xcb::x::Event::ButtonPress(evt)) => {
+                let win = evt.event();
+                let x = along_bar(&outputs.lock().unwrap(), win, evt.event_x(), evt.event_y());
 
-                let paint = paints
-                    .iter()
-                    .filter(|p: &&Paint| p.win == win && p.left <= x && p.right >= x)
-                    .min_by(|p1, p2| {
-                        (p1.right - p1.left)
-                            .partial_cmp(&(p2.right - p2.left))
-                            .unwrap()
+                let (interval, policy) = outputs
+                    .lock()
+                    .unwrap()
+                    .by_window(win)
+                    .map_or((300, ClickOutput::All), |o| {
+                        (o.cfg.double_click_interval, o.cfg.click_output)
                     });
+                let paints = paints.lock().unwrap();
+
+                if let Some(p) = paint_at(&paints, win, x) {
+                    if let Some(button) = MouseButton::from_x_detail(evt.detail()) {
+                        let state = evt.state();
+                        let handlers = p.area.on_click.iter().filter(|h| h.matches(&button, state));
 
-                if let Some(p) = paint {
-                    use bergamot::MouseButton;
-
-                    let button = match evt.detail() {
-                        1 => Some(MouseButton::Left),
-                        2 => Some(MouseButton::Middle),
-                        3 => Some(MouseButton::Right),
-                        4 => Some(MouseButton::ScrollUp),
-                        5 => Some(MouseButton::ScrollDown),
-                        6 => Some(MouseButton::ScrollLeft),
-                        7 => Some(MouseButton::ScrollRight),
-                        _ => None,
-                    };
-
-                    if let Some(button) = button {
-                        let handlers = p.area.on_click.iter().filter(|h| h.button == button);
-
-                        for handler in handlers {
-                            println!("{}", handler.output);
+                        let placeholders = [
+                            ("{x}", evt.event_x().to_string()),
+                            ("{rel_x}", format!("{:.0}", x - p.left)),
+                            ("{button}", button_name(&button)),
+                            ("{monitor}", p.monitor.to_string()),
+                        ];
+
+                        let count = if button.is_scroll() {
+                            let count = match scrolls {
+                                Some((w, left, b, n))
+                                    if w == win && left == p.left && b == button =>
+                                {
+                                    n + 1
+                                }
+                                _ => 1,
+                            };
+                            scrolls = Some((win, p.left, button, count));
+                            count
+                        } else {
+                            1
+                        };
+
+                        let render = |output: String| {
+                            if events_json {
+                                let event = serde_json::json!({
+                                    "tag": p.tag,
+                                    "button": button,
+                                    "x": evt.event_x(),
+                                    "monitor": p.monitor,
+                                    "output": output,
+                                });
+                                event.to_string()
+                            } else {
+                                output
+                            }
+                        };
+                        let fire = |handlers: Vec<&ClickHandler>| -> Vec<String> {
+                            let outputs = handlers
+                                .into_iter()
+                                .map(|h| expand_placeholders(&h.output, &placeholders));
+                            policy.apply(outputs).into_iter().map(render).collect()
+                        };
+
+                        let (doubles, singles): (Vec<_>, Vec<_>) =
+                            handlers.filter(|h| h.scrolled_enough(count)).partition(|h| h.double);
+                        let singles = fire(singles);
+
+                        if doubles.is_empty() {
+                            for line in singles {
+                                emit_click(exec_clicks, &line);
+                            }
+                        } else {
+                            let mut pending = pending_click.lock().unwrap();
+                            let second = pending.as_ref().is_some_and(|c| {
+                                c.win == win
+                                    && c.left == p.left
+                                    && c.button == button
+                                    && evt.time().wrapping_sub(c.time) <= interval
+                            });
+
+                            if second {
+                                *pending = None;
+                                for line in fire(doubles) {
+                                    emit_click(exec_clicks, &line);
+                                }
+                            } else {
+                                // Hold the single click back in case a second one follows.
+                                click_generation += 1;
+                                let generation = click_generation;
+                                *pending = Some(PendingClick {
+                                    win,
+                                    left: p.left,
+                                    button,
+                                    time: evt.time(),
+                                    generation,
+                                });
+
+                                let pending_click = Arc::clone(&pending_click);
+                                std::thread::spawn(move || {
+                                    let delay = std::time::Duration::from_millis(interval.into());
+                                    std::thread::sleep(delay);
+                                    let mut pending = pending_click.lock().unwrap();
+                                    if pending.as_ref().is_some_and(|c| c.generation == generation)
+                                    {
+                                        *pending = None;
+                                        for line in singles {
+                                            emit_click(exec_clicks, &line);
+                                        }
+                                    }
+                                });
+                            }
                         }
                     }
                 }
 	    },
 	    _ => {}
 	}
-        conn.0.flush().expect("Failed to flush connection");
     }
-    Ok(())
 }
@@ -0,0 +1,99 @@
+//! The i3bar protocol, as spoken by i3status, i3blocks and friends.
+//!
+//! The stream opens with a `{"version":1,...}` header and then an endless JSON array, one status
+//! line per element. Each status line is an array of blocks, and every block becomes a widget.
+
+use crate::{Alignment, Area, Colour, Colours, Widget};
+use std::collections::HashMap;
+
+#[derive(serde::Deserialize, Debug)]
+struct Block {
+    full_text: String,
+    #[serde(default)]
+    color: Option<Colour>,
+    #[serde(default)]
+    background: Option<Colour>,
+    /// Either a width in pixels or a string whose width to reserve; only the former is used.
+    #[serde(default)]
+    min_width: Option<serde_json::Value>,
+    #[serde(default)]
+    align: Option<Align>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    instance: Option<String>,
+    #[serde(default)]
+    markup: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+impl Block {
+    fn into_widget(self, index: usize) -> Widget {
+        let tag = match (self.name, self.instance) {
+            (Some(name), Some(instance)) => format!("{}:{}", name, instance),
+            (Some(name), None) => name,
+            _ => format!("i3bar-{}", index),
+        };
+        // i3bar puts the whole status line on the right.
+        let alignment = match self.align {
+            Some(Align::Left) => Alignment::Left,
+            Some(Align::Center) => Alignment::Center,
+            Some(Align::Right) | None => Alignment::Right,
+        };
+
+        Widget {
+            tag,
+            alignment,
+            content: vec![Area {
+                text: self.full_text,
+                colours: Colours {
+                    fg: self.color,
+                    bg: self.background,
+                },
+                markup: self.markup.as_deref() == Some("pango"),
+                ..Area::default()
+            }],
+            min_width: self.min_width.and_then(|w| w.as_f64()),
+            ..Widget::default()
+        }
+    }
+}
+
+/// Parses one line of an i3bar stream. Lines that carry no status line, such as the header and
+/// the array's opening bracket, give `None`.
+///
+/// Blocks are tagged by `name` and `instance`. Updates go to the first widget with a tag, so
+/// blocks that share one are tagged by their position instead.
+pub fn parse_line(line: &str) -> Result<Option<Vec<Widget>>, serde_json::Error> {
+    let line = line.trim();
+    if line.is_empty() || line == "[" || line == "]" || line.starts_with('{') {
+        return Ok(None);
+    }
+
+    // Every status line after the first is written as ",[...]".
+    let line = line.strip_prefix(',').unwrap_or(line).trim_end_matches(',');
+    let blocks: Vec<Block> = serde_json::from_str(line)?;
+    let mut widgets: Vec<Widget> = blocks
+        .into_iter()
+        .enumerate()
+        .map(|(i, block)| block.into_widget(i))
+        .collect();
+
+    let mut uses = HashMap::new();
+    for widget in &widgets {
+        *uses.entry(widget.tag.clone()).or_insert(0) += 1;
+    }
+    for (i, widget) in widgets.iter_mut().enumerate() {
+        if uses[&widget.tag] > 1 {
+            widget.tag = format!("i3bar-{}", i);
+        }
+    }
+    Ok(Some(widgets))
+}
@@ -0,0 +1,209 @@
+//! Lemonbar's format strings, one status line per line of input.
+//!
+//! Supported escapes, each written as `%{...}`, several to a block if separated by spaces:
+//!
+//! - `l`, `c` and `r` start the left, centered and right-aligned parts of the bar.
+//! - `F<colour>` and `B<colour>` set the text and background colours; `F-` and `B-` reset them.
+//!   Colours are `#RGB` or `#RRGGBB`, optionally with the alpha first as `#ARGB` or `#AARRGGBB`.
+//! - `R` swaps the text and background colours.
+//! - `+u`, `-u` and `!u` turn underlining on, off, or toggle it. `U<colour>` is accepted but
+//!   the underline always takes the text colour.
+//! - `A<button>:<command>:` makes the text up to the matching `A` clickable, printing `command`
+//!   when `button` (1 to 9, left by default) is pressed. Colons in the command are written `\:`.
+//!
+//! Other escapes, such as the overline and monitor selection, are skipped.
+
+use crate::{Alignment, Area, ClickHandler, Colour, Colours, MouseButton, Widget};
+use log::warn;
+
+#[derive(Debug)]
+struct Parser {
+    fg: Option<Colour>,
+    bg: Option<Colour>,
+    underline: bool,
+    /// The open clickable regions, innermost last. `None` for one with an unknown button, so
+    /// that it still pairs with its closing `A`.
+    actions: Vec<Option<ClickHandler>>,
+    alignment: usize,
+    /// The areas of the left, centered and right-aligned parts, in that order.
+    areas: [Vec<Area>; 3],
+}
+
+const ALIGNMENTS: [(&str, Alignment); 3] = [
+    ("left", Alignment::Left),
+    ("center", Alignment::Center),
+    ("right", Alignment::Right),
+];
+
+/// Splits `s` at the end of the attribute it starts with.
+fn token(s: &str) -> (&str, &str) {
+    s.split_at(s.find([' ', '}']).unwrap_or(s.len()))
+}
+
+/// Reads a colour as lemonbar writes it, with any alpha before the red. Colour names are taken
+/// as they are elsewhere.
+fn colour(value: &str) -> Option<Colour> {
+    let digits = match value.strip_prefix('#') {
+        Some(digits) if digits.is_ascii() => digits,
+        Some(_) => return None,
+        None => return value.parse().ok(),
+    };
+    let hex: String = match digits.len() {
+        // #ARGB is short for #AARRGGBB, as #RGB is for #RRGGBB.
+        4 => digits.chars().flat_map(|c| [c, c]).collect(),
+        _ => digits.to_string(),
+    };
+    match hex.len() {
+        8 => format!("#{}{}", &hex[2..], &hex[..2]).parse().ok(),
+        _ => format!("#{}", hex).parse().ok(),
+    }
+}
+
+impl Parser {
+    fn new() -> Self {
+        Parser {
+            fg: None,
+            bg: None,
+            underline: false,
+            actions: Vec::new(),
+            alignment: 0,
+            areas: Default::default(),
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        self.areas[self.alignment].push(Area {
+            text: text.to_string(),
+            colours: Colours {
+                fg: self.fg,
+                bg: self.bg,
+            },
+            underline: self.underline,
+            on_click: self.actions.iter().flatten().cloned().collect(),
+            ..Area::default()
+        });
+    }
+
+    /// Applies the attributes of a `%{...}` block, returning the input after its closing brace.
+    fn block<'a>(&mut self, mut s: &'a str) -> &'a str {
+        loop {
+            s = s.trim_start_matches(' ');
+            let mut chars = s.chars();
+            let rest = match chars.next() {
+                None => return s,
+                Some('}') => return chars.as_str(),
+                Some(c @ ('l' | 'c' | 'r')) => {
+                    self.alignment = match c {
+                        'l' => 0,
+                        'c' => 1,
+                        _ => 2,
+                    };
+                    chars.as_str()
+                }
+                Some(c @ ('F' | 'B')) => {
+                    let (value, rest) = token(chars.as_str());
+                    let colour = if value == "-" { None } else { colour(value) };
+                    if value != "-" && colour.is_none() {
+                        warn!("Bad lemonbar colour <{}>, using the default", value);
+                    }
+                    if c == 'F' {
+                        self.fg = colour;
+                    } else {
+                        self.bg = colour;
+                    }
+                    rest
+                }
+                Some('R') => {
+                    std::mem::swap(&mut self.fg, &mut self.bg);
+                    chars.as_str()
+                }
+                Some(c @ ('+' | '-' | '!')) => {
+                    let (attribute, rest) = token(chars.as_str());
+                    if attribute == "u" {
+                        self.underline = match c {
+                            '+' => true,
+                            '-' => false,
+                            _ => !self.underline,
+                        };
+                    }
+                    rest
+                }
+                Some('A') => self.action(chars.as_str()),
+                Some(_) => token(s).1,
+            };
+            s = rest;
+        }
+    }
+
+    /// Handles what follows an `A`: either opening a clickable region or closing the last one.
+    fn action<'a>(&mut self, s: &'a str) -> &'a str {
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, rest) = s.split_at(digits);
+        let rest = match rest.strip_prefix(':') {
+            Some(rest) => rest,
+            None => {
+                self.actions.pop();
+                return rest;
+            }
+        };
+
+        let mut command = String::new();
+        let mut chars = rest.char_indices();
+        let mut end = rest.len();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' if rest[i + 1..].starts_with(':') => {
+                    command.push(':');
+                    chars.next();
+                }
+                ':' => {
+                    end = i + 1;
+                    break;
+                }
+                c => command.push(c),
+            }
+        }
+
+        // Lemonbar numbers buttons as X does; no number means the left button.
+        let button = match number {
+            "" => Some(MouseButton::Left),
+            n => n.parse().ok().and_then(MouseButton::from_x_detail),
+        };
+        self.actions.push(button.map(|button| ClickHandler {
+            button,
+            output: command,
+            modifiers: Vec::new(),
+            scroll_threshold: None,
+            double: false,
+        }));
+        &rest[end..]
+    }
+
+    fn finish(self) -> Vec<Widget> {
+        IntoIterator::into_iter(self.areas)
+            .zip(ALIGNMENTS)
+            .filter(|(areas, _)| !areas.is_empty())
+            .map(|(content, (name, alignment))| Widget {
+                tag: format!("lemonbar-{}", name),
+                alignment,
+                content,
+                ..Widget::default()
+            })
+            .collect()
+    }
+}
+
+/// Parses one line of lemonbar input into the widgets making up the whole bar.
+pub fn parse_line(line: &str) -> Vec<Widget> {
+    let mut parser = Parser::new();
+    let mut rest = line;
+    while let Some(start) = rest.find("%{") {
+        parser.text(&rest[..start]);
+        rest = parser.block(&rest[start + 2..]);
+    }
+    parser.text(rest);
+    parser.finish()
+}
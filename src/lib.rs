@@ -1,10 +1,64 @@
+use log::warn;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use xcb::x::{Window, Screen, InternAtomReply};
 use xcb::Xid;
 
+pub mod i3bar;
+pub mod lemonbar;
+mod names;
+
 pub mod error {
     #[derive(Debug)]
     pub enum Error {
-	Xcb(xcb::Error)
+	Xcb(xcb::Error),
+        Io(std::io::Error),
+        /// A human-readable description of a bad configuration value.
+        Config(String),
+        Cairo(cairo::Error),
+        /// The screen offers no visual to draw a bar with.
+        NoVisual,
+        /// The X server reported no screens.
+        NoScreen,
+        /// The X server lacks an extension bergamot can't do without.
+        MissingExtension(xcb::Extension),
+    }
+
+    impl std::fmt::Display for Error {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Xcb(e) => write!(f, "X error: {}", e),
+                Self::Io(e) => write!(f, "I/O error: {}", e),
+                Self::Config(msg) => f.write_str(msg),
+                Self::Cairo(e) => write!(f, "Cairo error: {}", e),
+                Self::NoVisual => f.write_str("The screen has no visual to draw with"),
+                Self::NoScreen => f.write_str("The X server has no screens"),
+                Self::MissingExtension(ext) => {
+                    write!(f, "The X server doesn't support the {:?} extension", ext)
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for Error {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                Self::Xcb(e) => Some(e),
+                Self::Io(e) => Some(e),
+                Self::Cairo(e) => Some(e),
+                Self::Config(_) | Self::NoVisual | Self::NoScreen | Self::MissingExtension(_) => {
+                    None
+                }
+            }
+        }
+    }
+
+    impl From<cairo::Error> for Error {
+        fn from(e: cairo::Error) -> Self {
+            Self::Cairo(e)
+        }
     }
 
     impl From<xcb::Error> for Error {
@@ -13,6 +67,21 @@ pub mod error {
         }
     }
 
+    impl From<std::io::Error> for Error {
+        fn from(e: std::io::Error) -> Self {
+            Self::Io(e)
+        }
+    }
+
+    impl From<cairo::IoError> for Error {
+        fn from(e: cairo::IoError) -> Self {
+            match e {
+                cairo::IoError::Cairo(e) => Self::Cairo(e),
+                cairo::IoError::Io(e) => Self::Io(e),
+            }
+        }
+    }
+
     impl From<xcb::ConnError> for Error {
 	fn from(e: xcb::ConnError) -> Self {
 	    Self::Xcb(xcb::Error::Connection(e))
@@ -21,33 +90,65 @@ pub mod error {
 
 }
 
-#[derive(serde::Deserialize, Debug, Clone)]
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 #[serde(tag = "type", content = "value")]
 #[serde(rename_all = "lowercase")]
 pub enum Constraint {
     Monitor(MonitorConstraint),
+    /// Only show on the monitor RandR reports as primary.
+    Primary,
 }
 
-#[derive(serde::Deserialize, Debug, Clone, Copy)]
-#[serde(transparent)]
-pub struct MonitorConstraint(usize);
+/// A monitor, either by its index in reading order or by its RandR output name (e.g. "DP-1").
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum MonitorConstraint {
+    Number(usize),
+    Name(String),
+}
 
 impl MonitorConstraint {
-    pub fn number(&self) -> usize {
-        self.0
+    pub fn number(&self) -> Option<usize> {
+        match self {
+            Self::Number(n) => Some(*n),
+            Self::Name(_) => None,
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Self::Number(_) => None,
+            Self::Name(name) => Some(name),
+        }
+    }
+
+    pub fn matches(&self, index: usize, name: &str) -> bool {
+        match self {
+            Self::Number(n) => *n == index,
+            Self::Name(n) => n == name,
+        }
     }
 }
 
-#[derive(serde::Deserialize, Debug, Default, Clone)]
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, Clone)]
 #[serde(transparent)]
 pub struct Constraints(Vec<Constraint>);
 
 impl Constraints {
-    pub fn monitor(&self) -> impl Iterator<Item = MonitorConstraint> + '_ {
+    pub fn monitor(&self) -> impl Iterator<Item = &MonitorConstraint> + '_ {
         self.0.iter().filter_map(|c| match c {
-            Constraint::Monitor(m) => Some(*m),
+            Constraint::Monitor(m) => Some(m),
+            _ => None,
         })
     }
+
+    pub fn primary(&self) -> bool {
+        self.0.iter().any(|c| matches!(c, Constraint::Primary))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
 #[derive(serde::Deserialize, Debug, Clone)]
@@ -56,9 +157,31 @@ pub struct Update {
     pub content: Vec<Area>,
 }
 
+/// Replaces the area at `index` in a widget's content, leaving the others alone.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct UpdateArea {
+    pub tag: String,
+    pub index: usize,
+    pub area: Area,
+}
+
 #[derive(serde::Deserialize, Debug, Clone)]
 pub struct Draw {
     pub widgets: Vec<Widget>,
+    /// Reject the draw if two widgets share a tag, rather than only warning.
+    #[serde(default)]
+    pub strict: bool,
+}
+
+/// Appends a widget, replacing in place any existing widget with the same tag.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct Add {
+    pub widget: Widget,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct Remove {
+    pub tag: String,
 }
 
 #[derive(serde::Deserialize, Debug, Clone)]
@@ -66,22 +189,62 @@ pub struct Draw {
 #[serde(tag = "type")]
 pub enum Command {
     Update(Update),
+    UpdateArea(UpdateArea),
     Draw(Draw),
+    Add(Add),
+    Remove(Remove),
+    /// Prints the current widgets and monitors to stdout as a single line of JSON.
+    Query,
+    /// Repaints every bar now without changing any widget. Harmless to repeat.
+    Redraw,
 }
 
-#[derive(serde::Deserialize, Debug, Clone)]
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Widget {
     #[serde(default)]
     pub tag: String,
+    #[serde(default)]
     pub alignment: Alignment,
     #[serde(default)]
     pub content: Vec<Area>,
     #[serde(default)]
     pub constraints: Constraints,
+    #[serde(default)]
+    pub colours: Colours,
+    #[serde(default)]
+    pub max_width: Option<f64>,
+    /// Each area of the widget reserves at least this much width, with its text centered.
+    #[serde(default)]
+    pub min_width: Option<f64>,
+    /// Makes the widget a gap of this many pixels, ignoring its content. A fill-aligned spacer
+    /// takes its share of the free space instead.
+    #[serde(default)]
+    pub spacer: Option<f64>,
+}
+
+impl Widget {
+    /// Whether the widget's constraints allow it on `output`, the `index`th in reading order.
+    pub fn shown_on(&self, index: usize, output: &Output) -> bool {
+        // Most widgets have no constraints, so skip the scans for them.
+        if self.constraints.is_empty() {
+            return true;
+        }
+        if self.constraints.primary() && !output.primary {
+            return false;
+        }
+
+        let mut monitors = self.constraints.monitor().peekable();
+        monitors.peek().is_none() || monitors.any(|m| m.matches(index, &output.name))
+    }
+
+    /// The width an area of this widget occupies on the bar.
+    pub fn reserved_width(&self, layout: &Layout) -> f64 {
+        self.min_width.map_or(layout.width, |min| layout.width.max(min))
+    }
 }
 
-#[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub enum MouseButton {
     Left,
@@ -91,19 +254,102 @@ pub enum MouseButton {
     ScrollDown,
     ScrollLeft,
     ScrollRight,
+    Back,
+    Forward,
 }
 
-#[derive(serde::Deserialize, Debug, Clone)]
+impl MouseButton {
+    /// The button for the `detail` of an X button event, e.g. 4 for scrolling up.
+    pub fn from_x_detail(detail: u8) -> Option<MouseButton> {
+        Some(match detail {
+            1 => Self::Left,
+            2 => Self::Middle,
+            3 => Self::Right,
+            4 => Self::ScrollUp,
+            5 => Self::ScrollDown,
+            6 => Self::ScrollLeft,
+            7 => Self::ScrollRight,
+            8 => Self::Back,
+            9 => Self::Forward,
+            _ => return None,
+        })
+    }
+
+    pub fn is_scroll(&self) -> bool {
+        matches!(
+            self,
+            Self::ScrollUp | Self::ScrollDown | Self::ScrollLeft | Self::ScrollRight
+        )
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Modifier {
+    Shift,
+    #[serde(alias = "ctrl")]
+    Control,
+    #[serde(alias = "mod1")]
+    Alt,
+    #[serde(alias = "mod4")]
+    Super,
+}
+
+impl Modifier {
+    const ALL: [Modifier; 4] = [Self::Shift, Self::Control, Self::Alt, Self::Super];
+
+    fn mask(self) -> xcb::x::KeyButMask {
+        match self {
+            Self::Shift => xcb::x::KeyButMask::SHIFT,
+            Self::Control => xcb::x::KeyButMask::CONTROL,
+            Self::Alt => xcb::x::KeyButMask::MOD1,
+            Self::Super => xcb::x::KeyButMask::MOD4,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct ClickHandler {
     pub button: MouseButton,
+    /// Printed on a click, with `{x}`, `{rel_x}`, `{button}` and `{monitor}` replaced by the
+    /// click's position on the bar and within the area, the button, and the monitor's index.
     pub output: String,
+    /// Exactly these modifiers must be held; an empty list only fires on a plain click.
+    #[serde(default)]
+    pub modifiers: Vec<Modifier>,
+    /// For scroll buttons, fire once per this many consecutive scrolls in the same direction
+    /// over the same area. Smooths out high-resolution wheels.
+    #[serde(default)]
+    pub scroll_threshold: Option<u32>,
+    /// Fire only on a double click. Single click handlers for the same button on the same area
+    /// are held back until the double click interval passes without a second click.
+    #[serde(default)]
+    pub double: bool,
+}
+
+impl ClickHandler {
+    /// Whether this handler fires for `button` pressed with the modifier `state` of the event.
+    /// Lock keys such as Caps Lock and Num Lock are ignored.
+    pub fn matches(&self, button: &MouseButton, state: xcb::x::KeyButMask) -> bool {
+        self.button == *button
+            && Modifier::ALL
+                .iter()
+                .all(|m| self.modifiers.contains(m) == state.contains(m.mask()))
+    }
+
+    /// Whether the `count`th consecutive scroll should fire this handler. Handlers for other
+    /// buttons always fire.
+    pub fn scrolled_enough(&self, count: u32) -> bool {
+        !self.button.is_scroll() || count % self.scroll_threshold.unwrap_or(1).max(1) == 0
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Colour {
     pub red: u8,
     pub green: u8,
     pub blue: u8,
+    pub alpha: u8,
 }
 
 pub struct BadHexFormat(String);
@@ -117,15 +363,42 @@ impl std::fmt::Debug for BadHexFormat {
 impl std::str::FromStr for Colour {
     type Err = BadHexFormat;
     fn from_str(value: &str) -> Result<Self, Self::Err> {
-        fn byte(s: &str) -> Result<u8, BadHexFormat> {
-            u8::from_str_radix(s, 16).map_err(|_| BadHexFormat(s.to_string()))
-        }
+        // from_str_radix would also take a sign, so check for digits first.
+        let byte = |s: &str| {
+            u8::from_str_radix(s, 16)
+                .ok()
+                .filter(|_| s.bytes().all(|b| b.is_ascii_hexdigit()))
+                .ok_or_else(|| BadHexFormat(value.to_string()))
+        };
+        let nibble = |s: &str| byte(&s.repeat(2));
 
-        if value.len() == 7 && &value[0..1] == "#" {
+        if let Some((red, green, blue)) = names::lookup(value) {
+            Ok(Colour {
+                red,
+                green,
+                blue,
+                alpha: 255,
+            })
+        } else if value.len() == 4 && value.is_ascii() && &value[0..1] == "#" {
+            Ok(Colour {
+                red: nibble(&value[1..2])?,
+                green: nibble(&value[2..3])?,
+                blue: nibble(&value[3..4])?,
+                alpha: 255,
+            })
+        } else if value.len() == 7 && value.is_ascii() && &value[0..1] == "#" {
+            Ok(Colour {
+                red: byte(&value[1..3])?,
+                green: byte(&value[3..5])?,
+                blue: byte(&value[5..7])?,
+                alpha: 255,
+            })
+        } else if value.len() == 9 && value.is_ascii() && &value[0..1] == "#" {
             Ok(Colour {
                 red: byte(&value[1..3])?,
                 green: byte(&value[3..5])?,
                 blue: byte(&value[5..7])?,
+                alpha: byte(&value[7..9])?,
             })
         } else {
             Err(BadHexFormat(value.to_string()))
@@ -142,24 +415,84 @@ impl<'de> serde::Deserialize<'de> for Colour {
         use std::fmt;
 
         struct RgbOrHex;
+
+        fn functional(value: &str, args: &str, with_alpha: bool) -> Result<Colour, String> {
+            let args = args
+                .strip_suffix(')')
+                .ok_or_else(|| format!("missing closing parenthesis in {}", value))?;
+            let parts: Vec<&str> = args.split(',').map(str::trim).collect();
+            let expected = if with_alpha { 4 } else { 3 };
+            if parts.len() != expected {
+                return Err(format!(
+                    "expected {} components in {}, found {}",
+                    expected,
+                    value,
+                    parts.len()
+                ));
+            }
+
+            let channel = |s: &str| {
+                s.parse::<i64>()
+                    .map(|c| c.clamp(0, 255) as u8)
+                    .map_err(|_| format!("bad colour channel '{}' in {}", s, value))
+            };
+            let alpha = if with_alpha {
+                let a = parts[3]
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|a| a.is_finite())
+                    .ok_or_else(|| {
+                        format!("bad alpha '{}' in {}, expected 0.0-1.0", parts[3], value)
+                    })?;
+                (a.clamp(0.0, 1.0) * 255.0).round() as u8
+            } else {
+                255
+            };
+
+            Ok(Colour {
+                red: channel(parts[0])?,
+                green: channel(parts[1])?,
+                blue: channel(parts[2])?,
+                alpha,
+            })
+        }
+
+        fn opaque() -> u8 {
+            255
+        }
+
         #[derive(serde::Deserialize)]
         pub struct RawColour {
             pub red: u8,
             pub green: u8,
             pub blue: u8,
+            #[serde(default = "opaque")]
+            pub alpha: u8,
         }
 
         impl<'de> de::Visitor<'de> for RgbOrHex {
             type Value = Colour;
 
             fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-                fmt.write_str("RGB map or hex colour code")
+                fmt.write_str("RGB map, hex colour code or rgb()/rgba() string")
             }
 
             fn visit_str<E: Error>(self, value: &str) -> Result<Self::Value, E> {
                 use std::str::FromStr;
 
-                Colour::from_str(value).map_err(|e| Error::custom(format!("{:?}", e)))
+                let value = value.trim();
+                if let Some(args) = value.strip_prefix("rgba(") {
+                    functional(value, args, true).map_err(Error::custom)
+                } else if let Some(args) = value.strip_prefix("rgb(") {
+                    functional(value, args, false).map_err(Error::custom)
+                } else {
+                    Colour::from_str(value).map_err(|e| {
+                        Error::custom(format!(
+                            "{:?}, expected a colour name, #RGB, #RRGGBB or #RRGGBBAA",
+                            e
+                        ))
+                    })
+                }
             }
 
             fn visit_map<M: MapAccess<'de>>(self, map: M) -> Result<Self::Value, M::Error> {
@@ -171,6 +504,7 @@ impl<'de> serde::Deserialize<'de> for Colour {
                     red: rc.red,
                     green: rc.green,
                     blue: rc.blue,
+                    alpha: rc.alpha,
                 })
             }
         }
@@ -179,6 +513,23 @@ impl<'de> serde::Deserialize<'de> for Colour {
     }
 }
 
+impl serde::Serialize for Colour {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let hex = if self.alpha == 255 {
+            format!("#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+        } else {
+            format!(
+                "#{:02x}{:02x}{:02x}{:02x}",
+                self.red, self.green, self.blue, self.alpha
+            )
+        };
+        serializer.serialize_str(&hex)
+    }
+}
+
 impl Colour {
     pub fn red_fraction(&self) -> f64 {
         let red: f64 = self.red.into();
@@ -194,9 +545,39 @@ impl Colour {
         let blue: f64 = self.blue.into();
         blue / 255.0
     }
+
+    pub fn alpha_fraction(&self) -> f64 {
+        let alpha: f64 = self.alpha.into();
+        alpha / 255.0
+    }
+
+    /// Moves each channel `amount` (0.0 to 1.0) of the way towards white.
+    pub fn lighten(&self, amount: f64) -> Colour {
+        self.mix(255.0, amount)
+    }
+
+    /// Moves each channel `amount` (0.0 to 1.0) of the way towards black.
+    pub fn darken(&self, amount: f64) -> Colour {
+        self.mix(0.0, amount)
+    }
+
+    fn mix(&self, target: f64, amount: f64) -> Colour {
+        let amount = amount.clamp(0.0, 1.0);
+        let channel = |c: u8| {
+            let c: f64 = c.into();
+            (c + (target - c) * amount).round().clamp(0.0, 255.0) as u8
+        };
+
+        Colour {
+            red: channel(self.red),
+            green: channel(self.green),
+            blue: channel(self.blue),
+            alpha: self.alpha,
+        }
+    }
 }
 
-#[derive(serde::Deserialize, Debug, Clone, Copy)]
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, Clone, Copy)]
 pub struct Colours {
     #[serde(default)]
     pub fg: Option<Colour>,
@@ -204,13 +585,7 @@ pub struct Colours {
     pub bg: Option<Colour>,
 }
 
-impl Default for Colours {
-    fn default() -> Self {
-        Self { fg: None, bg: None }
-    }
-}
-
-#[derive(serde::Deserialize, Default, Debug, Clone)]
+#[derive(serde::Deserialize, serde::Serialize, Default, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Area {
     #[serde(default)]
@@ -219,6 +594,116 @@ pub struct Area {
     pub colours: Colours,
     #[serde(default)]
     pub on_click: Vec<ClickHandler>,
+    /// Printed when the pointer moves onto the area.
+    #[serde(default)]
+    pub on_enter: Vec<String>,
+    /// Printed when the pointer moves off the area.
+    #[serde(default)]
+    pub on_leave: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_gradient")]
+    pub gradient: Option<(Colour, Colour)>,
+    /// Rounds the corners of the background by this many pixels, at most half its height.
+    #[serde(default)]
+    pub corner_radius: Option<f64>,
+    /// Widens the part of the area that takes clicks by this many pixels on each side, without
+    /// moving what is drawn. Overrides the bar's `click_padding`.
+    #[serde(default)]
+    pub click_padding: Option<f64>,
+    #[serde(default)]
+    pub markup: bool,
+    #[serde(default)]
+    pub bold: bool,
+    #[serde(default)]
+    pub italic: bool,
+    #[serde(default)]
+    pub underline: bool,
+    #[serde(default)]
+    pub direction: Option<Direction>,
+    /// A PNG drawn before the text at its natural size, or with the `svg` feature, an SVG
+    /// scaled to the bar height.
+    #[serde(default)]
+    pub image: Option<PathBuf>,
+}
+
+impl Area {
+    /// Whether the area would draw nothing at all: no text, no image and no colours of its own.
+    /// An area with only a background is a deliberate spacer, so it is not empty.
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+            && self.image.is_none()
+            && self.colours.fg.is_none()
+            && self.colours.bg.is_none()
+            && self.gradient.is_none()
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Ltr,
+    Rtl,
+    Auto,
+}
+
+fn deserialize_gradient<'de, D>(deserializer: D) -> Result<Option<(Colour, Colour)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum RawGradient {
+        Pair(Colour, Colour),
+        Map { from: Colour, to: Colour },
+    }
+
+    Ok(Option::<RawGradient>::deserialize(deserializer)?.map(|g| match g {
+        RawGradient::Pair(from, to) => (from, to),
+        RawGradient::Map { from, to } => (from, to),
+    }))
+}
+
+/// What a change to the widgets needs repainted.
+#[derive(Debug, Default)]
+pub enum Damage {
+    #[default]
+    None,
+    /// Only the areas of these widgets, provided nothing moved.
+    Tags(Vec<String>),
+    All,
+}
+
+impl Damage {
+    pub fn merge(self, other: Damage) -> Damage {
+        match (self, other) {
+            (Damage::All, _) | (_, Damage::All) => Damage::All,
+            (Damage::None, d) | (d, Damage::None) => d,
+            (Damage::Tags(mut a), Damage::Tags(b)) => {
+                a.extend(b);
+                Damage::Tags(a)
+            }
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        matches!(self, Damage::None)
+    }
+}
+
+/// Pairs of (centered, other) widget tags we have already warned about overlapping.
+static WARNED_OVERLAPS: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+fn warn_overlap(centered: &Widget, other: &Widget) {
+    let pair = (centered.tag.clone(), other.tag.clone());
+    let mut warned = WARNED_OVERLAPS.lock().unwrap();
+    if !warned.contains(&pair) {
+        warn!(
+            "Centered widget '{}' overlaps {:?}-aligned widget '{}'",
+            pair.0, other.alignment, pair.1
+        );
+        warned.push(pair);
+    }
 }
 
 #[derive(Debug)]
@@ -226,34 +711,41 @@ pub struct Paint {
     pub left: f64,
     pub right: f64,
     pub win: Window,
+    /// The tag of the widget the area belongs to.
+    pub tag: String,
     pub area: Area,
+    /// The index in reading order of the monitor the bar is on.
+    pub monitor: usize,
+    /// How far past `left` and `right` the pointer still counts as over the area.
+    pub click_padding: f64,
 }
 
-#[derive(serde::Deserialize, Debug, Clone)]
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum Alignment {
+    #[default]
     Left,
     Center,
     Right,
+    /// Shares whatever space is left between the left and right groups.
+    Fill,
 }
 
 impl Alignment {
     pub fn is_center(&self) -> bool {
-        match self {
-            Self::Center => true,
-            _ => false,
-        }
+        matches!(self, Self::Center)
     }
 
     pub fn is_right(&self) -> bool {
-        match self {
-            Self::Right => true,
-            _ => false,
-        }
+        matches!(self, Self::Right)
+    }
+
+    pub fn is_fill(&self) -> bool {
+        matches!(self, Self::Fill)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
 pub struct Rectangle {
     pub x: f64,
     pub y: f64,
@@ -262,6 +754,11 @@ pub struct Rectangle {
 }
 
 impl Rectangle {
+    /// The rectangle mirrored across the diagonal, swapping x with y and width with height.
+    fn transposed(&self) -> Rectangle {
+        Rectangle::new(self.y, self.x, self.height, self.width)
+    }
+
     pub fn new(
         x: impl Into<f64>,
         y: impl Into<f64>,
@@ -275,95 +772,758 @@ impl Rectangle {
             height: height.into(),
         }
     }
+
+    /// Whether the two rectangles share any columns.
+    fn overlaps_horizontally(&self, other: &Rectangle) -> bool {
+        self.x < other.x + other.width && other.x < self.x + self.width
+    }
+
+    /// Whether `other` lies entirely within this rectangle.
+    pub fn contains(&self, other: &Rectangle) -> bool {
+        other.x >= self.x
+            && other.y >= self.y
+            && other.x + other.width <= self.x + self.width
+            && other.y + other.height <= self.y + self.height
+    }
+}
+
+/// A connected monitor, as reported by RandR.
+#[derive(Debug, Clone)]
+pub struct Monitor {
+    pub rect: Rectangle,
+    /// The RandR output name, e.g. "DP-1".
+    pub name: String,
+    pub primary: bool,
 }
 
 #[derive(Debug)]
 pub struct Output {
     pub rect: Rectangle,
+    /// Where the window sits on the screen, border included.
+    pub frame: Rectangle,
+    pub name: String,
+    pub primary: bool,
+    /// The index of the monitor in reading order. Stacked bars share one.
+    pub monitor: usize,
+    /// The index in the config list of the config this bar was created from.
+    pub config_index: usize,
     pub win: Window,
     pub ctx: OutputContext,
     pub font: FontDescription,
-    pub cfg: Config
+    pub cfg: Config,
+    /// The bar height in pixels, resolved from `cfg.height` when the window was created.
+    pub height: u32,
+    pub layouts: RefCell<LayoutCache>,
 }
 
-#[derive(Debug)]
-pub struct OutputContext {
-    cairo: cairo::Context,
-}
+impl Output {
+    /// Applies a reloaded config to an existing output. The window is not recreated, so the
+    /// structural settings (height, position, border and window properties) are kept from the
+    /// current config.
+    pub fn reconfigure(&mut self, cfg: Config) {
+        self.font = FontDescription::new(&cfg.font_str);
+        self.cfg = Config {
+            height: self.cfg.height,
+            vertical_padding: self.cfg.vertical_padding,
+            position: self.cfg.position,
+            border_width: self.cfg.border_width,
+            border_colour: self.cfg.border_colour,
+            monitor: self.cfg.monitor,
+            wm_instance: self.cfg.wm_instance.clone(),
+            wm_class: self.cfg.wm_class.clone(),
+            all_desktops: self.cfg.all_desktops,
+            clickable: self.cfg.clickable,
+            click_through: self.cfg.click_through,
+            opacity: self.cfg.opacity,
+            ..cfg
+        };
+        self.layouts.get_mut().clear();
+    }
 
-#[derive(Debug)]
-pub struct Layout {
-    pango_layout: pango::Layout,
-    pub width: f64,
-    pub height: f64,
-}
+    /// A layout for `area`, reused from an earlier frame when its text and style are unchanged.
+    pub fn layout(&self, area: &Area, max_width: Option<f64>) -> Layout {
+        let key = LayoutKey {
+            text: area.text.clone(),
+            markup: area.markup,
+            bold: area.bold,
+            italic: area.italic,
+            underline: area.underline,
+            direction: area.direction,
+            max_width: max_width.map(f64::to_bits),
+            image: area.image.clone(),
+        };
 
-impl Layout {
-    pub fn new(ctx: &OutputContext, area: &Area, font: &pango::FontDescription) -> Self {
-        let layout =
-            pangocairo::create_layout(&ctx.cairo).expect("Failed to create pangocairo layout");
+        let mut layouts = self.layouts.borrow_mut();
+        let image = area
+            .image
+            .as_ref()
+            .and_then(|path| layouts.image(path, self.height));
+        layouts.get_or_insert(key, || {
+            let mut layout = Layout::new(&self.ctx, area, &self.font.0, &self.cfg);
+            if let Some(image) = image {
+                layout.set_image(image);
+            }
+            if let Some(max_width) = max_width {
+                layout.truncate(max_width);
+            }
+            layout
+        })
+    }
 
-        layout.set_font_description(Some(&font));
-        layout.set_text(&area.text);
+    /// Lays out and paints `widgets` on this bar, returning where each area ended up. With
+    /// [`Damage::Tags`], if every area kept its place since `previous`, only the named widgets
+    /// and any centred areas drawn over them are repainted.
+    pub fn render(&self, widgets: &[Widget], previous: &[Paint], damage: &Damage) -> Vec<Paint> {
+        let mut area_paints = vec![];
+
+        // A vertical bar is laid out as if it were horizontal, then turned on its side: widths
+        // become lengths down the bar, and text that is too wide for it is ellipsized.
+        let vertical = self.cfg.position.is_vertical();
+        let thickness = f64::from(self.height);
+        let turn = |r: &Rectangle| if vertical { r.transposed() } else { r.clone() };
+
+        // Spacers have no areas to lay out, only a width to claim.
+        type Entry<'a> = (&'a Widget, Option<(&'a Area, Layout)>);
+
+        let (centered, mut uncentered): (Vec<Entry>, _) = widgets
+            .iter()
+            .filter(|w| w.shown_on(self.monitor, self))
+            .flat_map(|w| {
+                // Empty areas would only leave a sliver of padding, unless the widget gives
+                // them a background.
+                let shown = move |a: &&Area| !a.is_empty() || w.colours.bg.is_some();
+                let max_width = match w.max_width {
+                    _ if !vertical => w.max_width,
+                    Some(max) => Some(max.min(thickness)),
+                    None => Some(thickness),
+                };
+                let areas = w
+                    .content
+                    .iter()
+                    .filter(move |a| w.spacer.is_none() && shown(a))
+                    .map(move |a| (w, Some((a, self.layout(a, max_width)))));
+                w.spacer.map(|_| (w, None)).into_iter().chain(areas)
+            })
+            .partition(|(w, _)| w.alignment.is_center());
 
-        let (w, h) = layout.pixel_size();
-        let area_width: f64 = (w + 10).into();
-        let layout_height: f64 = h.into();
+        let (right, mut uncentered): (Vec<Entry>, _) = uncentered
+            .drain(..)
+            .partition(|(w, _)| w.alignment.is_right());
 
-        Layout {
-            pango_layout: layout,
-            width: area_width,
-            height: layout_height,
-        }
-    }
+        let (fill, left): (Vec<Entry>, _) = uncentered
+            .drain(..)
+            .partition(|(w, _)| w.alignment.is_fill());
 
-    pub fn display(&self, ctx: &OutputContext) {
-        pangocairo::show_layout(&ctx.cairo, &self.pango_layout)
-    }
-}
+        let reserved_width = |(w, content): &Entry| match content {
+            Some((_, layout)) if vertical => {
+                let length = layout.height + 2.0 * self.cfg.vertical_padding;
+                w.min_width.map_or(length, |min| length.max(min))
+            }
+            Some((_, layout)) => w.reserved_width(layout),
+            None => w.spacer.unwrap_or(0.0),
+        };
+        let widths: Vec<(Alignment, f64)> = left
+            .iter()
+            .chain(right.iter())
+            .chain(fill.iter())
+            .chain(centered.iter())
+            .map(|entry| (entry.0.alignment.clone(), reserved_width(entry)))
+            .collect();
+        let length = if vertical {
+            self.rect.height
+        } else {
+            self.rect.width
+        };
+        let mut cursors = Cursors::new(length, thickness, &widths);
+        // Left areas stop at the right-aligned group, or at the centred one if that comes
+        // first.
+        let center_width: f64 = centered.iter().map(reserved_width).sum();
+        let left_end = if center_width > 0.0 {
+            cursors.right.min(cursors.center)
+        } else {
+            cursors.right
+        };
 
-impl OutputContext {
-    pub fn set_colour(&self, colour: &Colour) {
-        self.cairo.set_source_rgb(
-            colour.red_fraction(),
-            colour.green_fraction(),
-            colour.blue_fraction(),
-        )
-    }
+        let bar = cursors.as_rectangle();
+        let mut placed: Vec<(&Widget, Rectangle)> = vec![];
+        let mut planned = vec![];
 
-    pub fn fill(&self) {
-        self.cairo.fill().expect("Failed to fill");
-    }
+        for entry in left
+            .iter()
+            .chain(right.iter())
+            .chain(fill.iter())
+            .chain(centered.iter())
+        {
+            let (widget, area, layout) = match entry {
+                (widget, Some((area, layout))) => (*widget, *area, layout),
+                (widget, None) => {
+                    cursors.place(&widget.alignment, reserved_width(entry));
+                    continue;
+                }
+            };
+
+            let bg = area
+                .colours
+                .bg
+                .or(widget.colours.bg)
+                .unwrap_or(self.cfg.default_bg);
+            let fg = area
+                .colours
+                .fg
+                .or(widget.colours.fg)
+                .unwrap_or(self.cfg.default_fg);
+
+            let rect = cursors.place(&widget.alignment, reserved_width(entry));
+
+            let visible = match widget.alignment {
+                Alignment::Left => match self.cfg.overflow.visible(&rect, left_end) {
+                    Some(visible) => visible,
+                    None => continue,
+                },
+                _ => rect.clone(),
+            };
+
+            if widget.alignment.is_center() {
+                for (other, other_rect) in &placed {
+                    if visible.overlaps_horizontally(other_rect) {
+                        warn_overlap(widget, other);
+                    }
+                }
+            } else if !widget.alignment.is_fill() {
+                placed.push((widget, visible.clone()));
+            }
 
-    pub fn rectangle(&self, rect: &Rectangle) {
-        self.cairo.rectangle(
-            rect.x.into(),
-            rect.y.into(),
-            rect.width.into(),
-            rect.height.into(),
-        )
-    }
+            planned.push((widget, area, layout, bg, fg, rect, visible));
+        }
 
-    pub fn status(&self) {
-	let s = self.cairo.target();
-	s.flush();
-    }
+        let before: Vec<_> = previous
+            .iter()
+            .filter(|p| p.win == self.win)
+            .map(|p| (p.left, p.right, p.tag.as_str()))
+            .collect();
+        let after: Vec<_> = planned
+            .iter()
+            .map(|(w, .., v)| (v.x, v.x + v.width, w.tag.as_str()))
+            .collect();
+        let partial = matches!(damage, Damage::Tags(_)) && before == after;
+
+        let mut damaged: Vec<bool> = planned
+            .iter()
+            .map(|(widget, ..)| match damage {
+                Damage::Tags(tags) if partial => tags.contains(&widget.tag),
+                _ => true,
+            })
+            .collect();
+        // Centred areas are drawn over the rest, so one on top of a repainted area has to be
+        // repainted too. They come last, so this sees every other damaged area first.
+        for i in 0..planned.len() {
+            let (widget, .., visible) = &planned[i];
+            if widget.alignment.is_center() && !damaged[i] {
+                damaged[i] = planned
+                    .iter()
+                    .zip(&damaged)
+                    .any(|((.., other), &d)| d && other.overlaps_horizontally(visible));
+            }
+        }
 
-    pub fn move_to(&self, x: f64, y: f64) {
-        self.cairo.move_to(x, y)
-    }
-}
+        if !partial {
+            self.ctx.clear(&turn(&bar), &self.cfg.default_bg);
+        }
 
-#[derive(Debug)]
-pub struct Cursors {
-    pub top: f64,
-    pub bottom: f64,
+        let planned = planned.into_iter().zip(damaged);
+        for ((widget, area, layout, bg, fg, rect, visible), damaged) in planned {
+            area_paints.push(Paint {
+                left: visible.x,
+                right: visible.x + visible.width,
+                win: self.win,
+                tag: widget.tag.clone(),
+                area: (*area).clone(),
+                monitor: self.monitor,
+                click_padding: area.click_padding.unwrap_or(self.cfg.click_padding),
+            });
+
+            if !damaged {
+                continue;
+            }
+
+            let (rect, visible) = (turn(&rect), turn(&visible));
+            self.ctx.save();
+            self.ctx.clip_rectangle(&visible);
+
+            // Translucent backgrounds would otherwise blend with the area's previous contents.
+            if partial {
+                self.ctx.clear(&visible, &self.cfg.default_bg);
+            }
+
+            if let Some((from, to)) = &area.gradient {
+                self.ctx.set_gradient(&rect, from, to);
+            } else {
+                self.ctx.set_colour(&bg);
+            }
+            match area.corner_radius {
+                Some(radius) => self.ctx.rounded_rectangle(&rect, radius),
+                None => self.ctx.rectangle(&rect),
+            }
+            self.ctx.fill();
+
+            let x = rect.x + ((rect.width - layout.width) / 2.0).max(0.0) + self.cfg.padding;
+            layout.display_image(&self.ctx, x, &rect);
+
+            self.ctx.set_colour(&fg);
+            self.ctx.move_to(
+                x + layout.image_advance(),
+                layout.text_y(&rect, self.cfg.text_y_offset),
+            );
+
+            layout.display(&self.ctx);
+
+            self.ctx.restore();
+        }
+
+        self.ctx.present();
+        self.layouts.borrow_mut().prune();
+
+        area_paints
+    }
+}
+
+/// Everything about an area that affects its layout, on top of the output's font and config.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct LayoutKey {
+    text: String,
+    markup: bool,
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    direction: Option<Direction>,
+    max_width: Option<u64>,
+    image: Option<PathBuf>,
+}
+
+/// Layouts of the areas drawn on an output, so unchanged text isn't shaped again every frame.
+#[derive(Debug, Default)]
+pub struct LayoutCache {
+    /// Each layout, with whether it has been used since the last prune.
+    layouts: HashMap<LayoutKey, (Layout, bool)>,
+    /// Decoded images by path, or `None` for those that failed to load.
+    images: HashMap<PathBuf, Option<cairo::ImageSurface>>,
+}
+
+impl LayoutCache {
+    fn get_or_insert(&mut self, key: LayoutKey, layout: impl FnOnce() -> Layout) -> Layout {
+        let (layout, used) = self.layouts.entry(key).or_insert_with(|| (layout(), false));
+        *used = true;
+        layout.clone()
+    }
+
+    /// Drops the layouts that haven't been used since the previous prune.
+    pub fn prune(&mut self) {
+        self.layouts.retain(|_, (_, used)| std::mem::take(used));
+    }
+
+    pub fn clear(&mut self) {
+        self.layouts.clear();
+        self.images.clear();
+    }
+
+    /// The image at `path`, loaded on first use. SVGs are scaled to `height` pixels tall.
+    fn image(&mut self, path: &Path, height: u32) -> Option<cairo::ImageSurface> {
+        self.images
+            .entry(path.to_path_buf())
+            .or_insert_with(|| {
+                let image = if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("svg")) {
+                    load_svg(path, height)
+                } else {
+                    std::fs::File::open(path)
+                        .map_err(|e| e.to_string())
+                        .and_then(|mut file| {
+                            cairo::ImageSurface::create_from_png(&mut file)
+                                .map_err(|e| e.to_string())
+                        })
+                };
+                match image {
+                    Ok(image) => Some(image),
+                    Err(e) => {
+                        warn!("Failed to load image {}: {}", path.display(), e);
+                        None
+                    }
+                }
+            })
+            .clone()
+    }
+}
+
+#[cfg(feature = "svg")]
+fn load_svg(path: &Path, height: u32) -> Result<cairo::ImageSurface, String> {
+    use resvg::{tiny_skia, usvg};
+
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    let tree = usvg::Tree::from_data(&data, &usvg::Options::default()).map_err(|e| e.to_string())?;
+
+    let size = tree.size();
+    let scale = height as f32 / size.height();
+    let width = (size.width() * scale).ceil() as u32;
+    let mut pixmap = tiny_skia::Pixmap::new(width.max(1), height.max(1))
+        .ok_or_else(|| "image has no area".to_string())?;
+    resvg::render(&tree, tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+
+    // tiny-skia gives premultiplied RGBA bytes; cairo wants premultiplied ARGB words.
+    let (width, height) = (pixmap.width() as i32, pixmap.height() as i32);
+    let mut surface = cairo::ImageSurface::create(cairo::Format::ARgb32, width, height)
+        .map_err(|e| e.to_string())?;
+    let stride = surface.stride() as usize;
+    {
+        let mut out = surface.data().map_err(|e| e.to_string())?;
+        for (y, row) in pixmap.data().chunks(pixmap.width() as usize * 4).enumerate() {
+            for (x, px) in row.chunks(4).enumerate() {
+                let argb = u32::from_be_bytes([px[3], px[0], px[1], px[2]]);
+                let offset = y * stride + x * 4;
+                out[offset..offset + 4].copy_from_slice(&argb.to_ne_bytes());
+            }
+        }
+    }
+    Ok(surface)
+}
+
+#[cfg(not(feature = "svg"))]
+fn load_svg(_path: &Path, _height: u32) -> Result<cairo::ImageSurface, String> {
+    Err("SVG images need bergamot built with the svg feature".to_string())
+}
+
+#[derive(Debug)]
+pub struct OutputContext {
+    /// Draws into an off-screen buffer, shown on the window by [`OutputContext::present`].
+    cairo: cairo::Context,
+    /// The bar window, or `None` when drawing straight into an image.
+    window: Option<cairo::Context>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pango_layout: pango::Layout,
+    padding: f64,
+    pub width: f64,
+    pub height: f64,
+    /// Ink extents of the text in pixels, relative to the layout origin.
+    pub ink: Rectangle,
+    /// Logical extents of the text in pixels, relative to the layout origin.
+    pub logical: Rectangle,
+    /// Distance from the top of the layout to the first baseline, in pixels.
+    pub baseline: f64,
+    image: Option<cairo::ImageSurface>,
+}
+
+impl Layout {
+    pub fn new(
+        ctx: &OutputContext,
+        area: &Area,
+        font: &pango::FontDescription,
+        cfg: &Config,
+    ) -> Self {
+        let layout =
+            pangocairo::create_layout(&ctx.cairo).expect("Failed to create pangocairo layout");
+
+        let mut font = font.clone();
+        if area.bold {
+            font.set_weight(pango::Weight::Bold);
+        }
+        if area.italic {
+            font.set_style(pango::Style::Italic);
+        }
+
+        layout.set_font_description(Some(&font));
+
+        match area.direction {
+            Some(Direction::Auto) => layout.set_auto_dir(true),
+            Some(direction) => {
+                layout.set_auto_dir(false);
+                if let Some(pctx) = layout.context() {
+                    pctx.set_base_dir(match direction {
+                        Direction::Rtl => pango::Direction::Rtl,
+                        _ => pango::Direction::Ltr,
+                    });
+                    layout.context_changed();
+                }
+            }
+            None => {}
+        }
+        if area.markup {
+            match pango::parse_markup(&area.text, '\0') {
+                Ok(_) => layout.set_markup(&area.text),
+                Err(e) => {
+                    warn!("Failed to parse markup <{}>\nError: {}", area.text, e);
+                    layout.set_text(&area.text);
+                }
+            }
+        } else {
+            layout.set_text(&area.text);
+        }
+
+        if area.underline || cfg.letter_spacing.is_some() {
+            let attrs = layout.attributes().unwrap_or_default();
+            if area.underline {
+                attrs.insert(pango::AttrInt::new_underline(pango::Underline::Single));
+            }
+            if let Some(spacing) = cfg.letter_spacing {
+                attrs.insert(pango::AttrInt::new_letter_spacing(spacing));
+            }
+            layout.set_attributes(Some(&attrs));
+        }
+
+        let (w, h) = layout.pixel_size();
+        let area_width = f64::from(w) + 2.0 * cfg.padding;
+        let layout_height: f64 = h.into();
+
+        let (ink, logical) = layout.pixel_extents();
+        let baseline = f64::from(layout.baseline()) / f64::from(pango::SCALE);
+
+        Layout {
+            pango_layout: layout,
+            padding: cfg.padding,
+            width: area_width,
+            height: layout_height,
+            ink: Rectangle::new(ink.x(), ink.y(), ink.width(), ink.height()),
+            logical: Rectangle::new(logical.x(), logical.y(), logical.width(), logical.height()),
+            baseline,
+            image: None,
+        }
+    }
+
+    /// Puts `image` before the text, widening the layout to fit it.
+    pub fn set_image(&mut self, image: cairo::ImageSurface) {
+        self.image = Some(image);
+        self.width += self.image_advance();
+    }
+
+    /// How far the image pushes the text right: its width, plus a gap if there is text.
+    pub fn image_advance(&self) -> f64 {
+        match &self.image {
+            Some(image) if self.logical.width > 0.0 => f64::from(image.width()) + self.padding,
+            Some(image) => image.width().into(),
+            None => 0.0,
+        }
+    }
+
+    /// Draws the image with its left edge at `x`, centered vertically within `rect`.
+    pub fn display_image(&self, ctx: &OutputContext, x: f64, rect: &Rectangle) {
+        if let Some(image) = &self.image {
+            let y = rect.y + (rect.height - f64::from(image.height())) / 2.0;
+            ctx.cairo
+                .set_source_surface(image, x, y)
+                .expect("Failed to set image source");
+            ctx.cairo.paint().expect("Failed to paint image");
+        }
+    }
+
+    /// The y coordinate to move to so the logical extents are centered within `rect`,
+    /// nudged by `offset`.
+    pub fn text_y(&self, rect: &Rectangle, offset: f64) -> f64 {
+        rect.y + (rect.height - self.logical.height) / 2.0 - self.logical.y + offset
+    }
+
+    /// Ellipsizes the text so that the padded width fits within `max_width`.
+    pub fn truncate(&mut self, max_width: f64) {
+        if self.width <= max_width {
+            return;
+        }
+
+        let text_width = (max_width - 2.0 * self.padding - self.image_advance()).max(0.0);
+        self.pango_layout
+            .set_width((text_width * f64::from(pango::SCALE)) as i32);
+        self.pango_layout.set_ellipsize(pango::EllipsizeMode::End);
+
+        let (w, _) = self.pango_layout.pixel_size();
+        self.width = (f64::from(w) + 2.0 * self.padding + self.image_advance()).min(max_width);
+    }
+
+    pub fn display(&self, ctx: &OutputContext) {
+        pangocairo::show_layout(&ctx.cairo, &self.pango_layout)
+    }
+}
+
+impl OutputContext {
+    /// Resizes the window surface after its window was resized, with a blank back buffer of
+    /// the new size.
+    fn resize(
+        &mut self,
+        width: i32,
+        height: i32,
+        content: cairo::Content,
+    ) -> Result<(), error::Error> {
+        use std::convert::TryFrom;
+
+        let window = match &self.window {
+            Some(window) => window,
+            None => return Ok(()),
+        };
+        let surface = cairo::XCBSurface::try_from(window.target())
+            .expect("Bar windows draw on an XCB surface");
+        surface.set_size(width, height)?;
+        let buffer = surface.create_similar(content, width, height)?;
+        self.cairo = cairo::Context::new(&buffer)?;
+        Ok(())
+    }
+
+    pub fn set_colour(&self, colour: &Colour) {
+        self.set_colour_rgba(colour, colour.alpha_fraction())
+    }
+
+    /// Like `set_colour`, but ignores the colour's own alpha in favour of `alpha`.
+    pub fn set_colour_rgba(&self, colour: &Colour, alpha: f64) {
+        self.cairo.set_source_rgba(
+            colour.red_fraction(),
+            colour.green_fraction(),
+            colour.blue_fraction(),
+            alpha.clamp(0.0, 1.0),
+        )
+    }
+
+    pub fn fill(&self) {
+        self.cairo.fill().expect("Failed to fill");
+    }
+
+    pub fn fill_gradient(&self, rect: &Rectangle, from: &Colour, to: &Colour) {
+        self.set_gradient(rect, from, to);
+        self.rectangle(rect);
+        self.fill();
+    }
+
+    /// Sets the source to a horizontal gradient across `rect`, from `from` to `to`.
+    pub fn set_gradient(&self, rect: &Rectangle, from: &Colour, to: &Colour) {
+        let gradient = cairo::LinearGradient::new(rect.x, 0.0, rect.x + rect.width, 0.0);
+        for (offset, colour) in [(0.0, from), (1.0, to)] {
+            gradient.add_color_stop_rgba(
+                offset,
+                colour.red_fraction(),
+                colour.green_fraction(),
+                colour.blue_fraction(),
+                colour.alpha_fraction(),
+            );
+        }
+
+        self.cairo
+            .set_source(&gradient)
+            .expect("Failed to set gradient source");
+    }
+
+    pub fn rectangle(&self, rect: &Rectangle) {
+        self.cairo
+            .rectangle(rect.x, rect.y, rect.width, rect.height)
+    }
+
+    /// Like `rectangle`, with the corners rounded off. The radius is clamped to half the
+    /// smaller side.
+    pub fn rounded_rectangle(&self, rect: &Rectangle, radius: f64) {
+        use std::f64::consts::{FRAC_PI_2, PI};
+
+        let r = radius.min(rect.width / 2.0).min(rect.height / 2.0).max(0.0);
+        let (left, top) = (rect.x + r, rect.y + r);
+        let (right, bottom) = (rect.x + rect.width - r, rect.y + rect.height - r);
+
+        self.cairo.new_sub_path();
+        self.cairo.arc(right, top, r, -FRAC_PI_2, 0.0);
+        self.cairo.arc(right, bottom, r, 0.0, FRAC_PI_2);
+        self.cairo.arc(left, bottom, r, FRAC_PI_2, PI);
+        self.cairo.arc(left, top, r, PI, 3.0 * FRAC_PI_2);
+        self.cairo.close_path();
+    }
+
+    pub fn save(&self) {
+        self.cairo.save().expect("Failed to save context");
+    }
+
+    pub fn restore(&self) {
+        self.cairo.restore().expect("Failed to restore context");
+    }
+
+    /// Restricts drawing to `rect` until the next `restore`.
+    pub fn clip_rectangle(&self, rect: &Rectangle) {
+        self.rectangle(rect);
+        self.cairo.clip();
+    }
+
+    pub fn status(&self) {
+	let s = self.cairo.target();
+	s.flush();
+    }
+
+    /// Fills `rect` with `colour`, replacing what was there instead of blending with it.
+    pub fn clear(&self, rect: &Rectangle, colour: &Colour) {
+        self.save();
+        self.cairo.set_operator(cairo::Operator::Source);
+        self.set_colour(colour);
+        self.rectangle(rect);
+        self.fill();
+        self.restore();
+    }
+
+    /// Copies the finished frame from the buffer to the window in one go.
+    pub fn present(&self) {
+        self.cairo.target().flush();
+        if let Some(window) = &self.window {
+            // Replace rather than blend, so translucent frames don't pile up on each other.
+            window.set_operator(cairo::Operator::Source);
+            window
+                .set_source_surface(&self.cairo.target(), 0.0, 0.0)
+                .expect("Failed to set buffer source");
+            window.paint().expect("Failed to paint buffer");
+            window.target().flush();
+        }
+    }
+
+    /// Writes the current frame as a PNG. Only works for contexts from [`create_image_output`].
+    pub fn write_png(&self, stream: &mut impl std::io::Write) -> Result<(), error::Error> {
+        use std::convert::TryFrom;
+
+        let image = cairo::ImageSurface::try_from(self.cairo.target())
+            .map_err(|_| error::Error::Config("Only image outputs can be saved as PNG".into()))?;
+        image.write_to_png(stream)?;
+        Ok(())
+    }
+
+    pub fn move_to(&self, x: f64, y: f64) {
+        self.cairo.move_to(x, y)
+    }
+}
+
+#[derive(Debug)]
+pub struct Cursors {
+    pub top: f64,
+    pub bottom: f64,
     pub left: f64,
     pub center: f64,
     pub right: f64,
+    /// The width given to each fill-aligned area.
+    pub fill: f64,
 }
 
 impl Cursors {
+    /// Cursors for a bar `width` by `height` pixels that will hold areas with these alignments
+    /// and reserved widths.
+    pub fn new(width: f64, height: f64, areas: &[(Alignment, f64)]) -> Self {
+        let total = |aligned: fn(&Alignment) -> bool| -> f64 {
+            areas.iter().filter(|(a, _)| aligned(a)).map(|(_, w)| w).sum()
+        };
+        let left_width = total(|a| matches!(a, Alignment::Left));
+        let right_start = width - total(Alignment::is_right);
+        let fills = areas.iter().filter(|(a, _)| a.is_fill()).count();
+
+        let fill = if fills == 0 {
+            0.0
+        } else {
+            ((right_start - left_width) / fills as f64).max(0.0)
+        };
+
+        Cursors {
+            top: 0.0,
+            bottom: height,
+            left: 0.0,
+            center: (width / 2.0) - (total(Alignment::is_center) / 2.0),
+            right: right_start,
+            fill,
+        }
+    }
+
     pub fn bump_left(&mut self, by: f64) -> (f64, f64) {
         let old = self.left;
         self.left += by;
@@ -383,84 +1543,820 @@ impl Cursors {
     }
 
     pub fn make_bounding_rectangle(&mut self, widget: &Widget, layout: &Layout) -> Rectangle {
-        let (left, right) = match widget.alignment {
-            Alignment::Left => self.bump_left(layout.width),
-            Alignment::Right => self.bump_right(layout.width),
+        self.place(&widget.alignment, widget.reserved_width(layout))
+    }
+
+    /// Claims `width` pixels for the next area with `alignment`, returning where it goes.
+    pub fn place(&mut self, alignment: &Alignment, width: f64) -> Rectangle {
+        let (left, right) = match alignment {
+            Alignment::Left => self.bump_left(width),
+            Alignment::Right => self.bump_right(width),
+            // These follow the left areas, so they continue from the left cursor into the gap.
+            Alignment::Fill => self.bump_left(self.fill),
             // These are done after all other areas so they can overwrite previously painted areas.
-            Alignment::Center => self.bump_center(layout.width),
+            Alignment::Center => self.bump_center(width),
+        };
+
+        Rectangle::new(left, self.top, right - left, self.bottom - self.top)
+    }
+
+    pub fn as_rectangle(&self) -> Rectangle {
+        Rectangle::new(
+            self.left,
+            self.top,
+            self.right - self.left,
+            self.bottom - self.top,
+        )
+    }
+}
+
+/// Where areas with these alignments and reserved widths go on a bar `width` by `height`
+/// pixels, in the same order. This is the placement [`Output::render`] does, before it clips
+/// or drops areas that overflow.
+pub fn place_areas(areas: &[(Alignment, f64)], width: f64, height: f64) -> Vec<Rectangle> {
+    let mut cursors = Cursors::new(width, height, areas);
+
+    // Fill areas continue from wherever the left areas end, so those go first, as in `render`.
+    let mut order: Vec<usize> = (0..areas.len()).collect();
+    order.sort_by_key(|&i| match areas[i].0 {
+        Alignment::Left | Alignment::Right => 0,
+        Alignment::Fill => 1,
+        Alignment::Center => 2,
+    });
+
+    let mut rectangles = vec![Rectangle::new(0, 0, 0, 0); areas.len()];
+    for i in order {
+        let (alignment, reserved) = &areas[i];
+        rectangles[i] = cursors.place(alignment, *reserved);
+    }
+    rectangles
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "Config::default_height")]
+    pub height: Height,
+    /// Space above and below the text when the height is automatic.
+    #[serde(default = "Config::default_vertical_padding")]
+    pub vertical_padding: f64,
+    #[serde(rename = "font", default = "Config::default_font")]
+    pub font_str: String,
+    #[serde(rename = "bg", default = "Config::default_bg")]
+    pub default_bg: Colour,
+    #[serde(rename = "fg", default = "Config::default_fg")]
+    pub default_fg: Colour,
+    /// Horizontal space on either side of each area's text. The historical value is 5.0.
+    #[serde(default = "Config::default_padding")]
+    pub padding: f64,
+    /// Extra space between letters, in Pango units (1024 per point).
+    #[serde(default)]
+    pub letter_spacing: Option<i32>,
+    /// Added to the computed text position; positive values move text down.
+    #[serde(default)]
+    pub text_y_offset: f64,
+    #[serde(default)]
+    pub overflow: Overflow,
+    #[serde(default)]
+    pub position: Position,
+    /// The longest gap between two clicks that still counts as a double click, in milliseconds.
+    #[serde(default = "Config::default_double_click_interval")]
+    pub double_click_interval: u32,
+    #[serde(default)]
+    pub click_output: ClickOutput,
+    /// Widens every area's clickable part by this many pixels on each side, for easier clicking
+    /// on narrow widgets.
+    #[serde(default)]
+    pub click_padding: f64,
+    /// Width of the line drawn around the bar, in pixels. The bar keeps its overall size.
+    #[serde(default)]
+    pub border_width: u16,
+    /// Colour of the border line; black if unset.
+    #[serde(default)]
+    pub border_colour: Option<Colour>,
+    /// Puts this bar on the monitor with this index in reading order, rather than taking the
+    /// next monitor in turn. Several bars on one monitor edge stack in config order.
+    #[serde(default)]
+    pub monitor: Option<usize>,
+    /// The instance half of the window's `WM_CLASS`, for window manager rules.
+    #[serde(default = "Config::default_wm_name")]
+    pub wm_instance: String,
+    /// The class half of the window's `WM_CLASS`.
+    #[serde(default = "Config::default_wm_name")]
+    pub wm_class: String,
+    /// Show the bar on every virtual desktop, as docks usually are.
+    #[serde(default = "Config::default_all_desktops")]
+    pub all_desktops: bool,
+    /// Whether the bar takes button presses. A purely decorative bar can turn this off to leave
+    /// clicks to the window manager.
+    #[serde(default = "Config::default_clickable")]
+    pub clickable: bool,
+    /// Lets the pointer through to the windows beneath the bar, except over areas with click or
+    /// hover handlers. Needs the X Shape extension.
+    #[serde(default)]
+    pub click_through: bool,
+    /// How opaque a compositor should draw the whole bar, from 0.0 to 1.0. Unlike a translucent
+    /// background, this doesn't need a 32-bit visual.
+    #[serde(default = "Config::default_opacity")]
+    pub opacity: f64,
+}
+
+impl Config {
+    fn default_height() -> Height {
+        Height::Fixed(18)
+    }
+
+    fn default_vertical_padding() -> f64 {
+        2.0
+    }
+
+    fn default_font() -> String {
+        "Iosevka Term 12".to_string()
+    }
+
+    fn default_bg() -> Colour {
+        Colour {
+            red: 0x33,
+            green: 0x32,
+            blue: 0x32,
+            alpha: 255,
+        }
+    }
+
+    fn default_fg() -> Colour {
+        Colour {
+            red: 0xa7,
+            green: 0xa5,
+            blue: 0xa5,
+            alpha: 255,
+        }
+    }
+
+    fn default_padding() -> f64 {
+        5.0
+    }
+
+    fn default_double_click_interval() -> u32 {
+        300
+    }
+
+    fn default_wm_name() -> String {
+        "bergamot".to_string()
+    }
+
+    fn default_all_desktops() -> bool {
+        true
+    }
+
+    fn default_clickable() -> bool {
+        true
+    }
+
+    fn default_opacity() -> f64 {
+        1.0
+    }
+
+    /// Reads a JSON file holding either a single config or a list of them, one per monitor.
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Vec<Config>, error::Error> {
+        use error::Error;
+
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .map_err(|e| Error::Config(format!("Cannot read {}: {}", path.display(), e)))?;
+        let value: serde_json::Value = serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|e| Error::Config(format!("{} is not valid JSON: {}", path.display(), e)))?;
+
+        // Report which field was wrong, e.g. "[1].bg: bad hex format #zzz".
+        let invalid = |e: serde_path_to_error::Error<serde_json::Error>| {
+            Error::Config(format!(
+                "Invalid config in {} at {}: {}",
+                path.display(),
+                e.path(),
+                e.inner()
+            ))
         };
 
-        Rectangle::new(left, self.top, right - left, self.bottom - self.top)
+        if value.is_array() {
+            serde_path_to_error::deserialize(value).map_err(invalid)
+        } else {
+            Ok(vec![serde_path_to_error::deserialize(value).map_err(invalid)?])
+        }
+    }
+
+    /// A builder starting from the same defaults a config file gets for missing fields.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config::default(),
+            error: None,
+        }
+    }
+}
+
+/// The same defaults a config file gets for missing fields.
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            height: Config::default_height(),
+            vertical_padding: Config::default_vertical_padding(),
+            font_str: Config::default_font(),
+            default_bg: Config::default_bg(),
+            default_fg: Config::default_fg(),
+            padding: Config::default_padding(),
+            letter_spacing: None,
+            text_y_offset: 0.0,
+            overflow: Overflow::default(),
+            position: Position::default(),
+            double_click_interval: Config::default_double_click_interval(),
+            click_output: ClickOutput::default(),
+            click_padding: 0.0,
+            border_width: 0,
+            border_colour: None,
+            monitor: None,
+            wm_instance: Config::default_wm_name(),
+            wm_class: Config::default_wm_name(),
+            all_desktops: Config::default_all_desktops(),
+            clickable: Config::default_clickable(),
+            click_through: false,
+            opacity: Config::default_opacity(),
+        }
+    }
+}
+
+/// Builds a [`Config`] in code. Colours are given as strings, like in a config file; the first
+/// one that fails to parse is reported by [`ConfigBuilder::build`].
+#[derive(Debug)]
+pub struct ConfigBuilder {
+    config: Config,
+    error: Option<error::Error>,
+}
+
+impl ConfigBuilder {
+    fn colour(&mut self, field: &str, value: impl AsRef<str>) -> Option<Colour> {
+        match value.as_ref().parse() {
+            Ok(colour) => Some(colour),
+            Err(e) => {
+                self.error.get_or_insert_with(|| {
+                    error::Error::Config(format!("Invalid {} colour: {:?}", field, e))
+                });
+                None
+            }
+        }
+    }
+
+    pub fn height(mut self, height: Height) -> Self {
+        self.config.height = height;
+        self
+    }
+
+    pub fn vertical_padding(mut self, vertical_padding: f64) -> Self {
+        self.config.vertical_padding = vertical_padding;
+        self
+    }
+
+    pub fn font(mut self, font: impl Into<String>) -> Self {
+        self.config.font_str = font.into();
+        self
+    }
+
+    pub fn default_bg(mut self, colour: impl AsRef<str>) -> Self {
+        if let Some(colour) = self.colour("bg", colour) {
+            self.config.default_bg = colour;
+        }
+        self
+    }
+
+    pub fn default_fg(mut self, colour: impl AsRef<str>) -> Self {
+        if let Some(colour) = self.colour("fg", colour) {
+            self.config.default_fg = colour;
+        }
+        self
+    }
+
+    pub fn padding(mut self, padding: f64) -> Self {
+        self.config.padding = padding;
+        self
+    }
+
+    pub fn letter_spacing(mut self, letter_spacing: i32) -> Self {
+        self.config.letter_spacing = Some(letter_spacing);
+        self
+    }
+
+    pub fn text_y_offset(mut self, text_y_offset: f64) -> Self {
+        self.config.text_y_offset = text_y_offset;
+        self
+    }
+
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.config.overflow = overflow;
+        self
+    }
+
+    pub fn position(mut self, position: Position) -> Self {
+        self.config.position = position;
+        self
+    }
+
+    pub fn double_click_interval(mut self, milliseconds: u32) -> Self {
+        self.config.double_click_interval = milliseconds;
+        self
+    }
+
+    pub fn click_output(mut self, click_output: ClickOutput) -> Self {
+        self.config.click_output = click_output;
+        self
+    }
+
+    pub fn click_padding(mut self, click_padding: f64) -> Self {
+        self.config.click_padding = click_padding;
+        self
+    }
+
+    pub fn border(mut self, width: u16, colour: impl AsRef<str>) -> Self {
+        self.config.border_width = width;
+        self.config.border_colour = self.colour("border", colour);
+        self
+    }
+
+    pub fn monitor(mut self, monitor: usize) -> Self {
+        self.config.monitor = Some(monitor);
+        self
+    }
+
+    pub fn all_desktops(mut self, all_desktops: bool) -> Self {
+        self.config.all_desktops = all_desktops;
+        self
+    }
+
+    pub fn clickable(mut self, clickable: bool) -> Self {
+        self.config.clickable = clickable;
+        self
+    }
+
+    pub fn click_through(mut self, click_through: bool) -> Self {
+        self.config.click_through = click_through;
+        self
+    }
+
+    pub fn opacity(mut self, opacity: f64) -> Self {
+        self.config.opacity = opacity;
+        self
+    }
+
+    pub fn wm_class(mut self, instance: impl Into<String>, class: impl Into<String>) -> Self {
+        self.config.wm_instance = instance.into();
+        self.config.wm_class = class.into();
+        self
+    }
+
+    pub fn build(self) -> Result<Config, error::Error> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.config),
+        }
+    }
+}
+
+/// A bar height in pixels, or `"auto"` to fit the font's line height.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Height {
+    Fixed(u32),
+    Auto,
+}
+
+impl Height {
+    /// The height in pixels, measuring `font` if automatic.
+    pub fn pixels(&self, font: &FontDescription, vertical_padding: f64) -> u32 {
+        match self {
+            Self::Fixed(height) => *height,
+            Self::Auto => (font.line_height() + 2.0 * vertical_padding).ceil() as u32,
+        }
+    }
+}
+
+pub struct BadHeight(String);
+
+impl std::fmt::Debug for BadHeight {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bad height {}, expected pixels or \"auto\"", self.0)
+    }
+}
+
+impl std::str::FromStr for Height {
+    type Err = BadHeight;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "auto" {
+            Ok(Self::Auto)
+        } else {
+            value
+                .parse()
+                .map(Self::Fixed)
+                .map_err(|_| BadHeight(value.to_string()))
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Height {
+    fn deserialize<D>(deserializer: D) -> Result<Height, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::{self, Error};
+        use std::convert::TryFrom;
+        use std::fmt;
+
+        struct PixelsOrAuto;
+
+        impl<'de> de::Visitor<'de> for PixelsOrAuto {
+            type Value = Height;
+
+            fn expecting(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                fmt.write_str("height in pixels or \"auto\"")
+            }
+
+            fn visit_u64<E: Error>(self, value: u64) -> Result<Self::Value, E> {
+                u32::try_from(value)
+                    .map(Height::Fixed)
+                    .map_err(|_| Error::custom(format!("height {} is too large", value)))
+            }
+
+            fn visit_str<E: Error>(self, value: &str) -> Result<Self::Value, E> {
+                use std::str::FromStr;
+
+                Height::from_str(value).map_err(|e| Error::custom(format!("{:?}", e)))
+            }
+        }
+
+        deserializer.deserialize_any(PixelsOrAuto)
+    }
+}
+
+/// Which edge of its monitor a bar sits on. The bar's struts reserve the same edge, so
+/// maximized windows stop short of it.
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Position {
+    #[default]
+    Top,
+    Bottom,
+    /// Down the left edge, with areas stacked top to bottom: left-aligned ones at the top and
+    /// right-aligned ones at the bottom. The bar's `height` is then its width.
+    #[cfg(feature = "vertical")]
+    Left,
+}
+
+impl Position {
+    /// Whether the bar runs down a side of its monitor rather than across it.
+    pub fn is_vertical(&self) -> bool {
+        match self {
+            Self::Top | Self::Bottom => false,
+            #[cfg(feature = "vertical")]
+            Self::Left => true,
+        }
+    }
+}
+
+/// What happens to left-aligned areas that run into the centred or right-aligned group.
+/// Right-aligned and centred areas always keep their place.
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// Draw the part of the area before the group it runs into and cut off the rest.
+    #[default]
+    Clip,
+    /// Skip any area that does not fit entirely.
+    Drop,
+}
+
+impl Overflow {
+    /// The part of a left-aligned area at `rect` to draw when the space for left areas ends at
+    /// `end`, or `None` to skip it.
+    pub fn visible(self, rect: &Rectangle, end: f64) -> Option<Rectangle> {
+        if rect.x + rect.width <= end {
+            Some(rect.clone())
+        } else if self == Overflow::Clip && rect.x < end {
+            Some(Rectangle::new(rect.x, rect.y, end - rect.x, rect.height))
+        } else {
+            None
+        }
+    }
+}
+
+/// What a click fires when several handlers on the area match it.
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClickOutput {
+    /// Only the first matching handler.
+    First,
+    /// Every matching handler, one line each.
+    #[default]
+    All,
+    /// Every matching handler, with their outputs joined by `; ` into a single line.
+    Joined,
+}
+
+impl ClickOutput {
+    /// Applies the policy to the outputs of the matching handlers, in order.
+    pub fn apply(self, outputs: impl IntoIterator<Item = String>) -> Vec<String> {
+        let mut outputs = outputs.into_iter();
+        match self {
+            Self::First => outputs.next().into_iter().collect(),
+            Self::All => outputs.collect(),
+            Self::Joined => {
+                let joined = outputs.collect::<Vec<_>>().join("; ");
+                if joined.is_empty() {
+                    vec![]
+                } else {
+                    vec![joined]
+                }
+            }
+        }
+    }
+}
+
+unsafe impl Send for Output {}
+
+/// The bars, in the order they were created: by monitor in reading order, and the bars stacked
+/// on one monitor in config order.
+#[derive(Debug, Default)]
+pub struct Outputs(Vec<Output>);
+
+impl Outputs {
+    /// The `index`th bar.
+    pub fn get(&self, index: usize) -> Option<&Output> {
+        self.0.get(index)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Output> {
+        self.0.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Output> {
+        self.0.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The bars on the `monitor`th monitor in reading order.
+    pub fn on_monitor(&self, monitor: usize) -> impl Iterator<Item = &Output> {
+        self.iter().filter(move |o| o.monitor == monitor)
+    }
+
+    /// The bars on the monitor with this RandR output name.
+    pub fn by_name<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Output> {
+        self.iter().filter(move |o| o.name == name)
+    }
+
+    /// The bars on monitors matching `constraint`.
+    pub fn matching<'a>(
+        &'a self,
+        constraint: &'a MonitorConstraint,
+    ) -> impl Iterator<Item = &'a Output> {
+        self.iter().filter(move |o| constraint.matches(o.monitor, &o.name))
+    }
+
+    /// The bar drawn to `win`.
+    pub fn by_window(&self, win: Window) -> Option<&Output> {
+        self.iter().find(|o| o.win == win)
+    }
+}
+
+impl From<Vec<Output>> for Outputs {
+    fn from(outputs: Vec<Output>) -> Self {
+        Outputs(outputs)
     }
+}
 
-    pub fn as_rectangle(&self) -> Rectangle {
-        Rectangle::new(
-            self.left,
-            self.top,
-            self.right - self.left,
-            self.bottom - self.top,
-        )
+impl IntoIterator for Outputs {
+    type Item = Output;
+    type IntoIter = std::vec::IntoIter<Output>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Config {
-    pub height: u32,
-    pub font_str: String,
-    pub default_bg: Colour,
-    pub default_fg: Colour,
+impl<'a> IntoIterator for &'a Outputs {
+    type Item = &'a Output;
+    type IntoIter = std::slice::Iter<'a, Output>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
 }
 
-unsafe impl Send for Output {}
+/// A shared handle to the X connection. Clones are cheap and refer to the same connection.
+///
+/// libxcb locks internally, so requests, replies and events may be used from any thread;
+/// `xcb::Connection` is `Send + Sync` on that basis, and this handle inherits both.
+#[derive(Clone)]
+pub struct XcbConnection(pub std::sync::Arc<xcb::Connection>);
 
-pub struct XcbConnection(pub xcb::Connection);
-unsafe impl Send for XcbConnection {}
-unsafe impl Sync for XcbConnection {}
+impl XcbConnection {
+    /// Whether `extension` was found when connecting.
+    pub fn has_extension(&self, extension: xcb::Extension) -> bool {
+        self.0.active_extensions().any(|e| e == extension)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct FontDescription(pub pango::FontDescription);
 
 impl FontDescription {
+    /// Parses a comma-separated list of Pango font descriptions, picking the first whose
+    /// family is installed. Falls back to the last entry if none are.
     pub fn new(description: impl AsRef<str>) -> Self {
-        let fd = pango::FontDescription::from_string(description.as_ref());
-        Self(fd)
+        use pango::prelude::*;
+
+        let candidates: Vec<_> = description
+            .as_ref()
+            .split(',')
+            .map(|d| pango::FontDescription::from_string(d.trim()))
+            .collect();
+
+        let families: Vec<String> = pangocairo::FontMap::default()
+            .map(|fm| {
+                fm.list_families()
+                    .iter()
+                    .filter_map(|f| f.name())
+                    .map(|n| n.to_lowercase())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let available = candidates.iter().find(|fd| {
+            fd.family()
+                .map(|f| families.contains(&f.to_lowercase()))
+                .unwrap_or(false)
+        });
+
+        match available {
+            Some(fd) => Self(fd.clone()),
+            None => {
+                warn!(
+                    "None of the fonts in '{}' are available, falling back to the last",
+                    description.as_ref()
+                );
+                Self(candidates.last().cloned().unwrap_or_default())
+            }
+        }
     }
 }
 unsafe impl Send for FontDescription {}
 
+impl FontDescription {
+    /// The height of a line of text in this font, in pixels.
+    pub fn line_height(&self) -> f64 {
+        use pango::prelude::*;
+
+        let ctx = pangocairo::FontMap::default()
+            .and_then(|fm| fm.create_context())
+            .expect("Failed to create pango context");
+        let layout = pango::Layout::new(&ctx);
+        layout.set_font_description(Some(&self.0));
+        layout.set_text("Ag");
+
+        f64::from(layout.pixel_size().1)
+    }
+}
+
 impl XcbConnection {
     pub fn flush(&self) {
         self.0.flush().expect("Failed to flush connection");
     }
 }
 
+/// Connects to the X server with the extensions bergamot uses: RandR, which it needs, and Shape
+/// and XFixes, which some features use when available.
 pub fn get_connection() -> Result<XcbConnection, error::Error> {
-    let (conn, _) = xcb::Connection::connect_with_extensions(
-	None,
-	&[xcb::Extension::RandR],
-	&[]
-    )?;
-    Ok(XcbConnection(conn))
+    get_connection_with(
+        &[xcb::Extension::RandR],
+        &[xcb::Extension::Shape, xcb::Extension::XFixes],
+    )
+}
+
+/// Connects to the X server, failing if any of the `required` extensions is unavailable.
+/// Whether the `optional` ones were found can be checked with
+/// [`XcbConnection::has_extension`].
+pub fn get_connection_with(
+    required: &[xcb::Extension],
+    optional: &[xcb::Extension],
+) -> Result<XcbConnection, error::Error> {
+    // xcb panics on a missing mandatory extension, so ask for everything as optional and check
+    // afterwards.
+    let extensions: Vec<_> = required.iter().chain(optional).copied().collect();
+    let (conn, _) = xcb::Connection::connect_with_extensions(None, &[], &extensions)?;
+    let conn = XcbConnection(std::sync::Arc::new(conn));
+    match required.iter().find(|&&e| !conn.has_extension(e)) {
+        Some(&missing) => Err(error::Error::MissingExtension(missing)),
+        None => Ok(conn),
+    }
+}
+
+/// Limits the part of each `click_through` bar that takes pointer input to its areas with click
+/// or hover handlers, so that everywhere else the pointer reaches the windows beneath.
+pub fn update_input_shapes(conn: &XcbConnection, outputs: &Outputs, paints: &[Paint]) {
+    if !conn.has_extension(xcb::Extension::Shape) {
+        return;
+    }
+
+    for output in outputs.iter().filter(|o| o.cfg.click_through) {
+        let rectangles: Vec<_> = paints
+            .iter()
+            .filter(|p| p.win == output.win)
+            .filter(|p| {
+                let area = &p.area;
+                !(area.on_click.is_empty() && area.on_enter.is_empty() && area.on_leave.is_empty())
+            })
+            .map(|p| {
+                let left = (p.left - p.click_padding).max(0.0);
+                let length = p.right + p.click_padding - left;
+                let mut rect = Rectangle::new(left, 0, length, output.height);
+                if output.cfg.position.is_vertical() {
+                    rect = rect.transposed();
+                }
+                xcb::x::Rectangle {
+                    x: rect.x as i16,
+                    y: rect.y as i16,
+                    width: rect.width.ceil() as u16,
+                    height: rect.height.ceil() as u16,
+                }
+            })
+            .collect();
+
+        conn.0.send_request(&xcb::shape::Rectangles {
+            operation: xcb::shape::So::Set,
+            destination_kind: xcb::shape::Sk::Input,
+            ordering: xcb::x::ClipOrdering::Unsorted,
+            destination_window: output.win,
+            x_offset: 0,
+            y_offset: 0,
+            rectangles: &rectangles,
+        });
+    }
 }
 
-pub fn get_screen(conn: &'_ XcbConnection) -> &'_ Screen {
+pub fn get_screen(conn: &'_ XcbConnection) -> Result<&'_ Screen, error::Error> {
     conn.0
         .get_setup()
         .roots()
         .next()
-        .expect("Failed to get screen")
+        .ok_or(error::Error::NoScreen)
+}
+
+/// Collapses mirrored monitors: any monitor lying entirely within another is dropped in favour of
+/// the larger one, which inherits its primary flag. Identical rectangles keep the first seen.
+pub fn dedupe(monitors: Vec<Monitor>) -> Vec<Monitor> {
+    let mut kept: Vec<Monitor> = Vec::new();
+
+    for monitor in monitors {
+        if let Some(existing) = kept.iter_mut().find(|m| m.rect.contains(&monitor.rect)) {
+            existing.primary |= monitor.primary;
+            continue;
+        }
+
+        let primary = monitor.primary
+            || kept
+                .iter()
+                .any(|m| m.primary && monitor.rect.contains(&m.rect));
+        kept.retain(|m| !monitor.rect.contains(&m.rect));
+        kept.push(Monitor { primary, ..monitor });
+    }
+
+    kept
+}
+
+/// Orders monitors top-to-bottom, then left-to-right.
+fn reading_order(l: &Monitor, r: &Monitor) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+
+    l.rect
+        .y
+        .partial_cmp(&r.rect.y)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| l.rect.x.partial_cmp(&r.rect.x).unwrap_or(Ordering::Equal))
 }
 
 pub fn get_rectangles(
     conn: &XcbConnection,
     screen: &Screen,
-) -> Result<Vec<Rectangle>, error::Error> {
+) -> Result<Vec<Monitor>, error::Error> {
 
     let resources = conn.0.wait_for_reply(conn.0.send_request(&xcb::randr::GetScreenResourcesCurrent {
 	window: screen.root()
     }))?;
 
 
+    let primary = conn.0.wait_for_reply(conn.0.send_request(&xcb::randr::GetOutputPrimary {
+        window: screen.root(),
+    }))?.output();
+
     let outputs = resources.outputs();
 
     let mut crtcs = Vec::new();
@@ -479,13 +2375,14 @@ pub fn get_rectangles(
 		crtc: info.crtc(),
 		config_timestamp: xcb::x::CURRENT_TIME,
 	    });
-            crtcs.push(cookie);
+            let name = String::from_utf8_lossy(info.name()).into_owned();
+            crtcs.push((cookie, name, *output == primary));
         }
     }
 
-    let mut rectangles = Vec::new();
+    let mut monitors = Vec::new();
 
-    for crtc in crtcs {
+    for (crtc, name, primary) in crtcs {
         let info = conn.0.wait_for_reply(crtc)?;
         let rect = Rectangle {
             x: info.x().into(),
@@ -493,138 +2390,444 @@ pub fn get_rectangles(
             width: info.width().into(),
             height: info.height().into(),
         };
-        rectangles.push(rect);
+        monitors.push(Monitor {
+            rect,
+            name,
+            primary,
+        });
+    }
+
+    Ok(dedupe(monitors))
+}
+
+/// Asks for RandR screen change events on the root window, so that monitors being
+/// connected or disconnected can be handled.
+pub fn watch_monitors(conn: &XcbConnection, screen: &Screen) {
+    conn.0.send_request(&xcb::randr::SelectInput {
+        window: screen.root(),
+        enable: xcb::randr::NotifyMask::SCREEN_CHANGE,
+    });
+}
+
+/// Tears down outputs made by `create_output_windows`, destroying their windows.
+pub fn destroy_output_windows(conn: &XcbConnection, outputs: Outputs) {
+    for output in outputs {
+        let window = output.win;
+        // Release the cairo surface before the window it draws to.
+        drop(output);
+        conn.0.send_request(&xcb::x::DestroyWindow { window });
     }
+}
 
-    Ok(rectangles)
+/// Sets the EWMH and ICCCM properties that make a bar window a dock: its type, strut, title and
+/// WM_CLASS. Safe to repeat on a window that already has them.
+fn set_bar_properties(
+    conn: &XcbConnection,
+    win: Window,
+    config: &Config,
+    title: &str,
+    strut: &[u32; 12],
+) -> Result<(), error::Error> {
+    if let [window_type, dock, state, below, strut_partial, net_wm_name, utf8, desktop, opacity] =
+        &intern_atoms(
+            &conn.0,
+            &[
+                "_NET_WM_WINDOW_TYPE",
+                "_NET_WM_WINDOW_TYPE_DOCK",
+                "_NET_WM_STATE",
+                "_NET_WM_STATE_BELOW",
+                "_NET_WM_STRUT_PARTIAL",
+                "_NET_WM_NAME",
+                "UTF8_STRING",
+                "_NET_WM_DESKTOP",
+                "_NET_WM_WINDOW_OPACITY",
+            ],
+        )?[..]
+    {
+        conn.0.send_request(&xcb::x::ChangeProperty {
+            mode: xcb::x::PropMode::Replace,
+            window: win,
+            property: window_type.atom(),
+            r#type: xcb::x::ATOM_ATOM,
+            data: &[dock.atom()],
+        });
+        conn.0.send_request(&xcb::x::ChangeProperty {
+            mode: xcb::x::PropMode::Replace,
+            window: win,
+            property: state.atom(),
+            r#type: xcb::x::ATOM_ATOM,
+            data: &[below.atom()],
+        });
+        // Only the partial strut: the plain _NET_WM_STRUT would reserve the edge across every
+        // monitor, not just this one.
+        conn.0.send_request(&xcb::x::ChangeProperty {
+            mode: xcb::x::PropMode::Replace,
+            window: win,
+            property: strut_partial.atom(),
+            r#type: xcb::x::ATOM_CARDINAL,
+            data: strut,
+        });
+        // A reused window may have been on every desktop under its old config.
+        if config.all_desktops {
+            conn.0.send_request(&xcb::x::ChangeProperty {
+                mode: xcb::x::PropMode::Replace,
+                window: win,
+                property: desktop.atom(),
+                r#type: xcb::x::ATOM_CARDINAL,
+                data: &[0xFFFFFFFFu32],
+            });
+        } else {
+            conn.0.send_request(&xcb::x::DeleteProperty {
+                window: win,
+                property: desktop.atom(),
+            });
+        }
+        conn.0.send_request(&xcb::x::ChangeProperty {
+            mode: xcb::x::PropMode::Replace,
+            window: win,
+            property: xcb::x::ATOM_WM_CLASS,
+            r#type: xcb::x::ATOM_STRING,
+            data: format!("{}\0{}", config.wm_instance, config.wm_class).as_bytes(),
+        });
+        // Compositors treat a missing property as fully opaque.
+        if config.opacity < 1.0 {
+            conn.0.send_request(&xcb::x::ChangeProperty {
+                mode: xcb::x::PropMode::Replace,
+                window: win,
+                property: opacity.atom(),
+                r#type: xcb::x::ATOM_CARDINAL,
+                data: &[(config.opacity.max(0.0) * f64::from(u32::MAX)) as u32],
+            });
+        } else {
+            conn.0.send_request(&xcb::x::DeleteProperty {
+                window: win,
+                property: opacity.atom(),
+            });
+        }
+        conn.0.send_request(&xcb::x::ChangeProperty {
+            mode: xcb::x::PropMode::Replace,
+            window: win,
+            property: net_wm_name.atom(),
+            r#type: utf8.atom(),
+            data: title.as_bytes(),
+        });
+        conn.0.send_request(&xcb::x::ChangeProperty {
+            mode: xcb::x::PropMode::Replace,
+            window: win,
+            property: xcb::x::ATOM_WM_NAME,
+            r#type: xcb::x::ATOM_STRING,
+            data: title.as_bytes(),
+        });
+    }
+    Ok(())
 }
 
-fn intern_atoms(conn: &'_ xcb::Connection, names: &[&str]) -> Vec<InternAtomReply> {
+fn intern_atoms(
+    conn: &'_ xcb::Connection,
+    names: &[&str],
+) -> Result<Vec<InternAtomReply>, xcb::Error> {
     names
         .iter()
         .map(|n| conn.send_request(&xcb::x::InternAtom {
 	    only_if_exists: false,
-	    name: &n.as_bytes()
+	    name: n.as_bytes()
 	}))
-        .map(|c| conn.wait_for_reply(c).expect("Bad reply"))
+        .map(|c| conn.wait_for_reply(c))
         .collect()
 }
 
+/// A 32-bit TrueColor visual, for windows with an alpha channel.
+fn argb_visual(screen: &Screen) -> Option<&xcb::x::Visualtype> {
+    screen
+        .allowed_depths()
+        .filter(|depth| depth.depth() == 32)
+        .flat_map(|depth| depth.visuals())
+        .find(|visual| visual.class() == xcb::x::VisualClass::TrueColor)
+}
+
+/// The visual of the root window, from the list of those the screen supports.
+fn root_visual(screen: &Screen) -> Option<&xcb::x::Visualtype> {
+    screen
+        .allowed_depths()
+        .filter(|depth| depth.depth() == screen.root_depth())
+        .flat_map(|depth| depth.visuals())
+        .find(|visual| visual.visual_id() == screen.root_visual())
+}
+
+/// `colour` as a pixel value of a TrueColor `visual`, with any bits outside its colour masks
+/// holding alpha.
+fn argb_pixel(visual: &xcb::x::Visualtype, colour: &Colour) -> u32 {
+    let channel = |mask: u32, value: u8| {
+        if mask == 0 {
+            return 0;
+        }
+        let shift = mask.trailing_zeros();
+        let max = u64::from(mask >> shift);
+        (((u64::from(value) * max + 127) / 255) as u32) << shift
+    };
+    let alpha_mask = !(visual.red_mask() | visual.green_mask() | visual.blue_mask());
+
+    channel(visual.red_mask(), colour.red)
+        | channel(visual.green_mask(), colour.green)
+        | channel(visual.blue_mask(), colour.blue)
+        | channel(alpha_mask, colour.alpha)
+}
+
 pub fn create_output_windows(
     conn: &XcbConnection,
     screen: &Screen,
-    configs: &Vec<Config>,
-    mut rectangles: Vec<Rectangle>,
-) -> Vec<Output> {
+    configs: &[Config],
+    monitors: Vec<Monitor>,
+) -> Result<Outputs, error::Error> {
+    update_output_windows(conn, screen, configs, monitors, Outputs::default())
+}
+
+/// Like [`create_output_windows`], but keeps the windows in `existing` for bars from the same
+/// config, moving and resizing them as needed so they don't flicker. The rest are destroyed.
+pub fn update_output_windows(
+    conn: &XcbConnection,
+    screen: &Screen,
+    configs: &[Config],
+    mut monitors: Vec<Monitor>,
+    existing: Outputs,
+) -> Result<Outputs, error::Error> {
+    // A laptop with its lid shut may have no monitors until it is docked. Drawing resumes on
+    // the RandR notification for the new monitor.
+    if monitors.is_empty() {
+        warn!("No monitors are connected; waiting for one to appear");
+        destroy_output_windows(conn, existing);
+        return Ok(Outputs::default());
+    }
+
+    let mut existing = existing.0;
     let mut outputs = Vec::new();
 
-    rectangles.sort_by(|l, r| {
-        use std::cmp::Ordering;
+    // Reading order, so config N lands on the Nth monitor top-to-bottom, left-to-right.
+    monitors.sort_by(reading_order);
+
+    // Configs without a `monitor` go to the Nth monitor in turn, and monitors beyond the last
+    // reuse it, so a single config applies to every monitor. A monitor given configs by its
+    // index gets those instead.
+    let positional: Vec<usize> = (0..configs.len())
+        .filter(|&i| configs[i].monitor.is_none())
+        .collect();
+    if !positional.is_empty() && positional.len() != monitors.len() {
+        warn!(
+            "Found {} configs for {} monitors; extra monitors reuse the last config",
+            positional.len(),
+            monitors.len()
+        );
+    }
+    for config in configs {
+        if let Some(monitor) = config.monitor.filter(|&m| m >= monitors.len()) {
+            warn!(
+                "Ignoring config for monitor {}; there are only {} monitors",
+                monitor,
+                monitors.len()
+            );
+        }
+    }
 
-        if l.y < r.y && l.x < r.y {
-            Ordering::Less
-        } else if l.y < r.y || l.x < r.x {
-            Ordering::Less
+    let mut bars = Vec::new();
+    for (index, monitor) in monitors.iter().enumerate() {
+        let pinned: Vec<usize> = (0..configs.len())
+            .filter(|&i| configs[i].monitor == Some(index))
+            .collect();
+        if pinned.is_empty() {
+            if let Some(&i) = positional.get(index).or(positional.last()) {
+                bars.push((index, monitor, i));
+            }
         } else {
-            Ordering::Less
+            bars.extend(pinned.into_iter().map(|i| (index, monitor, i)));
         }
-    });
+    }
 
-    for (rectangle, config) in rectangles.iter().zip(configs) {
-        let win: Window = conn.0.generate_id();
+    // Bars sharing a monitor edge stack outwards in config order: the space already taken at
+    // each (monitor, position).
+    let mut taken: HashMap<(usize, Position), u32> = HashMap::new();
+
+    for (index, monitor, config_index) in bars {
+        let config = &configs[config_index];
+        let rectangle = &monitor.rect;
+        if config.click_through && !conn.has_extension(xcb::Extension::Shape) {
+            warn!("The X server has no Shape extension, so bars can't be click-through");
+        }
+
+        let font = FontDescription::new(&config.font_str);
+        let height = config.height.pixels(&font, config.vertical_padding);
+
+        let offset = taken.entry((index, config.position)).or_insert(0);
+        let stacked = *offset;
+        *offset += height;
+
+        let start_x = rectangle.x as u32;
+        let end_x = (rectangle.x + rectangle.width) as u32;
+        // The frame is where the window goes, border included. Struts are measured from the
+        // edge of the whole screen, not the monitor.
+        let (frame, strut) = match config.position {
+            Position::Top => {
+                let y = rectangle.y + f64::from(stacked);
+                let top = (y + f64::from(height)) as u32;
+                let frame = Rectangle::new(rectangle.x, y, rectangle.width, height);
+                (frame, [0, 0, top, 0, 0, 0, 0, 0, start_x, end_x, 0, 0])
+            }
+            Position::Bottom => {
+                let y = rectangle.y + rectangle.height - f64::from(stacked + height);
+                let bottom = (f64::from(screen.height_in_pixels()) - y).max(0.0) as u32;
+                let frame = Rectangle::new(rectangle.x, y, rectangle.width, height);
+                (frame, [0, 0, 0, bottom, 0, 0, 0, 0, 0, 0, start_x, end_x])
+            }
+            #[cfg(feature = "vertical")]
+            Position::Left => {
+                let x = rectangle.x + f64::from(stacked);
+                let left = (x + f64::from(height)) as u32;
+                let start_y = rectangle.y as u32;
+                let end_y = (rectangle.y + rectangle.height) as u32;
+                let frame = Rectangle::new(x, rectangle.y, height, rectangle.height);
+                (frame, [left, 0, 0, 0, start_y, end_y, 0, 0, 0, 0, 0, 0])
+            }
+        };
+        let title = match outputs.iter().filter(|o: &&Output| o.monitor == index).count() {
+            0 => format!("bergamot-{}", index),
+            n => format!("bergamot-{}-{}", index, n),
+        };
+
+        // The border is drawn outside the window, so shrink the window to keep the whole bar
+        // on its monitor.
+        let border = config.border_width;
+        let inner_width = (frame.width - 2.0 * f64::from(border)).max(1.0);
+        let inner_height = (frame.height - 2.0 * f64::from(border)).max(1.0);
+
+        let rect = if config.position.is_vertical() {
+            Rectangle::new(frame.x, frame.y, inner_width, inner_height)
+        } else {
+            Rectangle {
+                width: inner_width,
+                ..rectangle.clone()
+            }
+        };
+        let thickness = if config.position.is_vertical() {
+            inner_width
+        } else {
+            inner_height
+        };
 
+        // A bar for the same config keeps its window, so it doesn't flash; it is moved and
+        // resized if its monitor changed. Only settings fixed when the window was created
+        // need a new one.
+        let reusable = existing.iter().position(|o| {
+            o.config_index == config_index
+                && o.cfg.border_width == config.border_width
+                && o.cfg.border_colour == config.border_colour
+                && o.cfg.clickable == config.clickable
+                && o.cfg.click_through == config.click_through
+        });
+        if let Some(i) = reusable {
+            let mut output = existing.swap_remove(i);
+            if output.frame != frame {
+                // The same visual as when the window was created.
+                let content = match argb_visual(screen) {
+                    Some(_) => cairo::Content::ColorAlpha,
+                    None => cairo::Content::Color,
+                };
+                conn.0.send_request(&xcb::x::ConfigureWindow {
+                    window: output.win,
+                    value_list: &[
+                        xcb::x::ConfigWindow::X(frame.x as i32),
+                        xcb::x::ConfigWindow::Y(frame.y as i32),
+                        xcb::x::ConfigWindow::Width(inner_width as u32),
+                        xcb::x::ConfigWindow::Height(inner_height as u32),
+                    ],
+                });
+                output.ctx.resize(inner_width as i32, inner_height as i32, content)?;
+                output.frame = frame;
+            }
+            set_bar_properties(conn, output.win, config, &title, &strut)?;
+            output.rect = rect;
+            output.name = monitor.name.clone();
+            output.primary = monitor.primary;
+            output.monitor = index;
+            output.font = font;
+            output.cfg = config.clone();
+            output.height = thickness as u32;
+            output.layouts.get_mut().clear();
+            outputs.push(output);
+            continue;
+        }
+
+        // A 32-bit visual lets a compositor show the bar's alpha; without one, the bar is opaque
+        // and shares the root window's visual, which the default colormap belongs to.
+        let (visp, depth, colormap) = match argb_visual(screen) {
+            Some(visual) => {
+                let colormap: xcb::x::Colormap = conn.0.generate_id();
+                conn.0.send_request(&xcb::x::CreateColormap {
+                    alloc: xcb::x::ColormapAlloc::None,
+                    mid: colormap,
+                    window: screen.root(),
+                    visual: visual.visual_id(),
+                });
+                (visual, 32, Some(colormap))
+            }
+            None => {
+                let visual = root_visual(screen).ok_or(error::Error::NoVisual)?;
+                (visual, screen.root_depth(), None)
+            }
+        };
+        let translucent = colormap.is_some();
+
+        let black = Colour {
+            red: 0,
+            green: 0,
+            blue: 0,
+            alpha: 255,
+        };
+        let border_pixel = match config.border_colour {
+            _ if translucent => argb_pixel(visp, &config.border_colour.unwrap_or(black)),
+            Some(colour) => {
+                let scale = |c: u8| u16::from(c) * 257;
+                conn.0
+                    .wait_for_reply(conn.0.send_request(&xcb::x::AllocColor {
+                        cmap: screen.default_colormap(),
+                        red: scale(colour.red),
+                        green: scale(colour.green),
+                        blue: scale(colour.blue),
+                    }))?
+                    .pixel()
+            }
+            None => screen.black_pixel(),
+        };
+
+        let mut event_mask = xcb::x::EventMask::EXPOSURE
+            | xcb::x::EventMask::POINTER_MOTION
+            | xcb::x::EventMask::LEAVE_WINDOW;
+        if config.clickable {
+            event_mask |= xcb::x::EventMask::BUTTON_PRESS;
+        }
+        let mut value_list = vec![
+            xcb::x::Cw::BackPixel(if translucent { 0 } else { screen.black_pixel() }),
+            xcb::x::Cw::BorderPixel(border_pixel),
+            xcb::x::Cw::EventMask(event_mask),
+        ];
+        if let Some(colormap) = colormap {
+            value_list.push(xcb::x::Cw::Colormap(colormap));
+        }
+
+        let win: Window = conn.0.generate_id();
 	conn.0.send_request(&xcb::x::CreateWindow {
-	    depth: xcb::x::COPY_FROM_PARENT as u8,
+	    depth,
 	    wid: win,
 	    parent: screen.root(),
-	    x: rectangle.x as i16,
-	    y: rectangle.y as i16,
-	    width: rectangle.width as u16,
-	    height: config.height as u16,
-	    border_width: 0,
+	    x: frame.x as i16,
+	    y: frame.y as i16,
+	    width: inner_width as u16,
+	    height: inner_height as u16,
+	    border_width: border,
 	    class: xcb::x::WindowClass::InputOutput,
-	    visual: screen.root_visual(),
-	    value_list: &[
-                xcb::x::Cw::BackPixel(screen.black_pixel()),
-		xcb::x::Cw::EventMask(xcb::x::EventMask::EXPOSURE | xcb::x::EventMask::BUTTON_PRESS)
-            ],
+	    visual: visp.visual_id(),
+	    value_list: &value_list,
 	});
 
-        if let [window_type, dock, state, below, strut, strut_partial] = &intern_atoms(
-            &conn.0,
-            &[
-                "_NET_WM_WINDOW_TYPE",
-                "_NET_WM_WINDOW_TYPE_DOCK",
-                "_NET_WM_STATE",
-                "_NET_WM_STATE_BELOW",
-		"_NET_WM_STRUT",
-                "_NET_WM_STRUT_PARTIAL",
-            ],
-        )[..]
-        {
-	    conn.0.send_request(&xcb::x::ChangeProperty {
-		mode: xcb::x::PropMode::Replace,
-		window: win,
-		property: window_type.atom(),
-		r#type: xcb::x::ATOM_ATOM,
-		data: &[dock.atom()]
-	    });
-	    conn.0.send_request(&xcb::x::ChangeProperty {
-		mode: xcb::x::PropMode::Replace,
-		window: win,
-		property: state.atom(),
-		r#type: xcb::x::ATOM_ATOM,
-		data: &[below.atom()]
-	    });
-	    conn.0.send_request(&xcb::x::ChangeProperty {
-		mode: xcb::x::PropMode::Replace,
-		window: win,
-		property: strut.atom(),
-		r#type: xcb::x::ATOM_CARDINAL,
-		data: &[
-		    0, //left
-                    0, //right
-		    config.height, //top
-		    0, //bottom
-		]
-	    });
-	    conn.0.send_request(&xcb::x::ChangeProperty {
-		mode: xcb::x::PropMode::Replace,
-		window: win,
-		property: strut_partial.atom(),
-		r#type: xcb::x::ATOM_CARDINAL,
-		data: &[
-		    0, //left
-                    0, //right
-		    config.height, //top
-		    0, //bottom
-		    0, //left_start_y
-		    0, //left_end_y
-		    0, // right_start_y
-		    0, // right_end_y
-		    rectangle.x as u32, // top_start_x
-		    (rectangle.x + rectangle.width) as u32, // top_end_x
-		    0, // bottom_start_x
-		    0, // bottom_end_x
-		]
-	    });
-	    conn.0.send_request(&xcb::x::ChangeProperty {
-		mode: xcb::x::PropMode::Replace,
-		window: win,
-		property: xcb::x::ATOM_WM_CLASS,
-		r#type: xcb::x::ATOM_STRING,
-		data: "bergamot\0bergamot".as_bytes(),
-	    });
-        }
-
-        let visp = screen
-            .allowed_depths()
-            .next()
-            .expect("No allowed depths")
-            .visuals()
-	    .iter()
-	    .next()
-            .expect("No visuals");
+        set_bar_properties(conn, win, config, &title, &strut)?;
 
 	let cvis = unsafe {
 	    cairo::XCBVisualType::from_raw_none(
@@ -640,31 +2843,627 @@ pub fn create_output_windows(
             &ccon,
             &cwin,
             &cvis,
-            rectangle.width as i32,
-            rectangle.height as i32,
-        ).expect("Failed to create cairo surface");
+            inner_width as i32,
+            inner_height as i32,
+        )?;
 
-	let cctx = cairo::Context::new(&surface)
-	    .expect("Failed to create cairo context");
+	let cctx = cairo::Context::new(&surface)?;
+        let content = if translucent {
+            cairo::Content::ColorAlpha
+        } else {
+            cairo::Content::Color
+        };
+        let buffer = surface.create_similar(
+            content,
+            inner_width as i32,
+            inner_height as i32,
+        )?;
 	
         let ctx = OutputContext {
-            cairo: cctx
+            cairo: cairo::Context::new(&buffer)?,
+            window: Some(cctx),
         };
 
 	conn.0.send_request(&xcb::x::MapWindow {
 	    window: win
 	});
 
-	let font = FontDescription::new(&config.font_str);
-
         outputs.push(Output {
-            rect: rectangle.clone(),
+            rect,
+            frame,
+            name: monitor.name.clone(),
+            primary: monitor.primary,
+            monitor: index,
+            config_index,
             win,
             ctx,
 	    font,
-	    cfg: config.clone()
+	    cfg: config.clone(),
+            height: thickness as u32,
+            layouts: RefCell::default(),
         })
     }
 
-    outputs
+    destroy_output_windows(conn, Outputs(existing));
+    Ok(Outputs(outputs))
+}
+
+/// A bar `width` pixels wide that draws into an image instead of a window, so frames can be
+/// rendered without an X server. Its height comes from `config` as usual. A vertical bar is
+/// `width` pixels long instead.
+pub fn create_image_output(config: &Config, width: u32) -> Result<Output, error::Error> {
+    let font = FontDescription::new(&config.font_str);
+    let height = config.height.pixels(&font, config.vertical_padding);
+
+    let mut rect = Rectangle::new(0, 0, width, height);
+    if config.position.is_vertical() {
+        rect = rect.transposed();
+    }
+    let surface =
+        cairo::ImageSurface::create(cairo::Format::ARgb32, rect.width as i32, rect.height as i32)?;
+    let ctx = OutputContext {
+        cairo: cairo::Context::new(&surface)?,
+        window: None,
+    };
+
+    Ok(Output {
+        rect: rect.clone(),
+        frame: rect,
+        name: "image".into(),
+        primary: true,
+        monitor: 0,
+        config_index: 0,
+        win: Window::none(),
+        ctx,
+        font,
+        cfg: config.clone(),
+        height,
+        layouts: RefCell::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn colour(s: &str) -> Colour {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn colour_reads_alpha_from_rrggbbaa() {
+        let c = colour("#01020380");
+        assert_eq!((c.red, c.green, c.blue, c.alpha), (1, 2, 3, 0x80));
+        assert_eq!(colour("#010203").alpha, 255);
+        assert!("#aébcdef".parse::<Colour>().is_err());
+    }
+
+    #[test]
+    fn colour_expands_shorthand_hex() {
+        let c = colour("#fa0");
+        assert_eq!((c.red, c.green, c.blue, c.alpha), (0xff, 0xaa, 0x00, 255));
+    }
+
+    #[test]
+    fn colour_serializes_as_lowercase_hex() {
+        let c: Colour = serde_json::from_str("\"#A7A5A5\"").unwrap();
+        assert_eq!(serde_json::to_string(&c).unwrap(), "\"#a7a5a5\"");
+    }
+
+    #[test]
+    fn colour_serializes_alpha_only_when_translucent() {
+        let opaque = Colour {
+            red: 1,
+            green: 2,
+            blue: 3,
+            alpha: 255,
+        };
+        let translucent = Colour { alpha: 0x80, ..opaque };
+        assert_eq!(serde_json::to_string(&opaque).unwrap(), "\"#010203\"");
+        assert_eq!(serde_json::to_string(&translucent).unwrap(), "\"#01020380\"");
+    }
+
+    #[test]
+    fn colour_reads_rgb_and_rgba_strings() {
+        let parse = |s: &str| serde_json::from_value::<Colour>(serde_json::json!(s));
+        assert_eq!(parse("rgb(1, 2, 3)").unwrap(), colour("#010203"));
+        assert_eq!(parse("rgba(1, 2, 3, 0.5)").unwrap(), colour("#01020380"));
+        for bad in ["rgba(1,2,3,NaN)", "rgba(1,2,3,inf)", "rgba(1,2,3,-inf)"] {
+            let e = parse(bad).unwrap_err().to_string();
+            assert!(e.contains("bad alpha"), "{}: {}", bad, e);
+        }
+    }
+
+    #[test]
+    fn lighten_and_darken_span_the_colour_to_white_and_black() {
+        let rgba = |c: Colour| (c.red, c.green, c.blue, c.alpha);
+        let c = colour("#8040c080");
+        assert_eq!(rgba(c.lighten(0.0)), rgba(c));
+        assert_eq!(rgba(c.darken(0.0)), rgba(c));
+        assert_eq!(rgba(c.lighten(1.0)), (255, 255, 255, 0x80));
+        assert_eq!(rgba(c.darken(1.0)), (0, 0, 0, 0x80));
+    }
+
+    #[test]
+    fn text_y_offset_nudges_the_centred_position() {
+        let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, 1, 1).unwrap();
+        let layout = Layout {
+            pango_layout: pangocairo::create_layout(&cairo::Context::new(&surface).unwrap())
+                .unwrap(),
+            padding: 0.0,
+            width: 30.0,
+            height: 14.0,
+            ink: Rectangle::new(0, 4, 30, 9),
+            logical: Rectangle::new(0, 2, 30, 14),
+            baseline: 12.0,
+            image: None,
+        };
+        let rect = Rectangle::new(0, 10, 100, 40);
+
+        // 13px of space above and below the 14px logical box, which starts 2px down.
+        assert_eq!(layout.text_y(&rect, 0.0), 21.0);
+        assert_eq!(layout.text_y(&rect, 3.0), 24.0);
+        assert_eq!(layout.text_y(&rect, -2.0), 19.0);
+    }
+
+    #[test]
+    fn monitors_sort_in_reading_order() {
+        let mut monitors: Vec<Monitor> = [(1920, 1080), (1920, 0), (0, 1080), (0, 0)]
+            .iter()
+            .map(|&(x, y)| Monitor {
+                rect: Rectangle::new(x, y, 1920, 1080),
+                name: format!("{}x{}", x, y),
+                primary: false,
+            })
+            .collect();
+        monitors.sort_by(reading_order);
+
+        let positions: Vec<(f64, f64)> = monitors.iter().map(|m| (m.rect.x, m.rect.y)).collect();
+        assert_eq!(positions, [(0.0, 0.0), (1920.0, 0.0), (0.0, 1080.0), (1920.0, 1080.0)]);
+    }
+
+    #[test]
+    fn overflow_clips_or_drops_areas_past_the_end() {
+        let extent = |r: Option<Rectangle>| r.map(|r| (r.x, r.width));
+        let fits = Rectangle::new(0, 0, 80, 20);
+        let crosses = Rectangle::new(80, 0, 80, 20);
+        let past = Rectangle::new(160, 0, 80, 20);
+
+        assert_eq!(extent(Overflow::Clip.visible(&fits, 100.0)), Some((0.0, 80.0)));
+        assert_eq!(extent(Overflow::Drop.visible(&fits, 100.0)), Some((0.0, 80.0)));
+        assert_eq!(extent(Overflow::Clip.visible(&crosses, 100.0)), Some((80.0, 20.0)));
+        assert_eq!(extent(Overflow::Drop.visible(&crosses, 100.0)), None);
+        assert_eq!(extent(Overflow::Clip.visible(&past, 100.0)), None);
+        assert_eq!(extent(Overflow::Drop.visible(&past, 100.0)), None);
+    }
+
+    fn monitor(name: &str, x: u32, y: u32, width: u32, height: u32, primary: bool) -> Monitor {
+        Monitor {
+            rect: Rectangle::new(x, y, width, height),
+            name: name.to_string(),
+            primary,
+        }
+    }
+
+    fn names(monitors: &[Monitor]) -> Vec<&str> {
+        monitors.iter().map(|m| m.name.as_str()).collect()
+    }
+
+    #[test]
+    fn dedupe_keeps_one_of_identical_monitors() {
+        let monitors = dedupe(vec![
+            monitor("a", 0, 0, 1920, 1080, false),
+            monitor("b", 0, 0, 1920, 1080, false),
+        ]);
+        assert_eq!(names(&monitors), ["a"]);
+    }
+
+    #[test]
+    fn dedupe_keeps_the_larger_of_mirrored_monitors() {
+        let small = monitor("small", 0, 0, 1280, 720, true);
+        let large = monitor("large", 0, 0, 1920, 1080, false);
+        for monitors in [
+            vec![small.clone(), large.clone()],
+            vec![large.clone(), small.clone()],
+        ] {
+            let monitors = dedupe(monitors);
+            assert_eq!(names(&monitors), ["large"]);
+            assert!(monitors[0].primary);
+        }
+    }
+
+    #[test]
+    fn scroll_threshold_only_applies_to_scroll_buttons() {
+        let handler = |button| ClickHandler {
+            button,
+            output: String::new(),
+            modifiers: Vec::new(),
+            scroll_threshold: Some(2),
+            double: false,
+        };
+        let scroll = handler(MouseButton::ScrollUp);
+        assert!(!scroll.scrolled_enough(1));
+        assert!(scroll.scrolled_enough(2));
+        assert!(handler(MouseButton::Left).scrolled_enough(1));
+    }
+
+    #[test]
+    fn x_button_details_map_to_mouse_buttons() {
+        use MouseButton::*;
+
+        let buttons = (1..=9).map(MouseButton::from_x_detail).collect::<Vec<_>>();
+        let expected = [
+            Left,
+            Middle,
+            Right,
+            ScrollUp,
+            ScrollDown,
+            ScrollLeft,
+            ScrollRight,
+            Back,
+            Forward,
+        ];
+        assert_eq!(buttons, expected.map(Some));
+        for detail in [0, 10, 11, 255] {
+            assert_eq!(MouseButton::from_x_detail(detail), None);
+        }
+    }
+
+    /// `place_areas` for `areas` on a 300x20 bar, as `(x, y, width, height)`.
+    fn placed(areas: &[(Alignment, f64)]) -> Vec<(f64, f64, f64, f64)> {
+        place_areas(areas, 300.0, 20.0)
+            .iter()
+            .map(|r| (r.x, r.y, r.width, r.height))
+            .collect()
+    }
+
+    #[test]
+    fn widgets_default_to_left_but_reject_misspelt_alignment() {
+        let widget = |json| serde_json::from_value::<Widget>(json);
+        let plain = widget(serde_json::json!({"tag": "a"})).unwrap();
+        assert!(matches!(plain.alignment, Alignment::Left));
+        assert!(widget(serde_json::json!({"tag": "a", "alignmnet": "right"})).is_err());
+    }
+
+    #[test]
+    fn i3bar_skips_lines_without_a_status_line() {
+        for line in ["{\"version\":1,\"click_events\":true}", "[", "]", "", "  "] {
+            assert!(i3bar::parse_line(line).unwrap().is_none(), "{:?}", line);
+        }
+        assert!(i3bar::parse_line(",[{\"full_text\":").is_err());
+    }
+
+    #[test]
+    fn i3bar_reads_first_and_later_status_lines() {
+        let first = i3bar::parse_line(r#"[{"full_text":"a"}],"#).unwrap();
+        let first = first.unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].content[0].text, "a");
+
+        let later = i3bar::parse_line(r#",[{"full_text":"b"},{"full_text":"c"}]"#).unwrap();
+        let later = later.unwrap();
+        assert_eq!(later.len(), 2);
+        assert_eq!(later[1].content[0].text, "c");
+    }
+
+    #[test]
+    fn i3bar_blocks_become_widgets() {
+        let line = r##"[
+            {"full_text":"a","color":"#ff0000","background":"#00ff00","markup":"pango"},
+            {"full_text":"b","align":"left","markup":"none"},
+            {"full_text":"c","align":"center"}
+        ]"##;
+        let widgets = i3bar::parse_line(&line.replace('\n', "")).unwrap().unwrap();
+
+        let colours = &widgets[0].content[0].colours;
+        assert_eq!(colours.fg, Some(colour("#ff0000")));
+        assert_eq!(colours.bg, Some(colour("#00ff00")));
+        assert!(widgets[0].content[0].markup);
+        assert!(!widgets[1].content[0].markup);
+
+        assert!(widgets[0].alignment.is_right());
+        assert!(matches!(widgets[1].alignment, Alignment::Left));
+        assert!(widgets[2].alignment.is_center());
+    }
+
+    #[test]
+    fn i3bar_tags_blocks_by_name_and_instance() {
+        let line = r#"[
+            {"full_text":"","name":"disk","instance":"/"},
+            {"full_text":"","name":"disk","instance":"/home"},
+            {"full_text":"","name":"time"},
+            {"full_text":""},
+            {"full_text":"","name":"cpu"},
+            {"full_text":"","name":"cpu"}
+        ]"#;
+        let widgets = i3bar::parse_line(&line.replace('\n', "")).unwrap().unwrap();
+        let tags: Vec<&str> = widgets.iter().map(|w| w.tag.as_str()).collect();
+        let expected = [
+            "disk:/",
+            "disk:/home",
+            "time",
+            "i3bar-3",
+            "i3bar-4",
+            "i3bar-5",
+        ];
+        assert_eq!(tags, expected);
+    }
+
+    #[test]
+    fn lemonbar_reads_colours_with_alpha_first() {
+        let fg = |line: &str| lemonbar::parse_line(line)[0].content[0].colours.fg;
+        assert_eq!(fg("%{F#123}a"), Some(colour("#112233")));
+        assert_eq!(fg("%{F#102030}a"), Some(colour("#102030")));
+        assert_eq!(fg("%{F#8123}a"), Some(colour("#11223388")));
+        assert_eq!(fg("%{F#80102030}a"), Some(colour("#10203080")));
+        assert_eq!(fg("%{F#102030}%{F-}a"), None);
+        for bad in ["%{F#12}a", "%{F#1020304}a", "%{F#+1+2+3}a", "%{F#ééé}a"] {
+            assert_eq!(fg(bad), None, "{}", bad);
+        }
+
+        let colours = &lemonbar::parse_line("%{F#ff0000 B#00ff00}a%{R}b")[0].content;
+        assert_eq!(colours[0].colours.fg, Some(colour("#ff0000")));
+        assert_eq!(colours[0].colours.bg, Some(colour("#00ff00")));
+        assert_eq!(colours[1].colours.fg, Some(colour("#00ff00")));
+        assert_eq!(colours[1].colours.bg, Some(colour("#ff0000")));
+    }
+
+    #[test]
+    fn lemonbar_splits_the_bar_into_aligned_widgets() {
+        let widgets = lemonbar::parse_line("a%{r}b%{c}c%{l}d");
+        let parts: Vec<(&str, Vec<&str>)> = widgets
+            .iter()
+            .map(|w| {
+                let texts = w.content.iter().map(|a| a.text.as_str()).collect();
+                (w.tag.as_str(), texts)
+            })
+            .collect();
+        assert_eq!(
+            parts,
+            [
+                ("lemonbar-left", vec!["a", "d"]),
+                ("lemonbar-center", vec!["c"]),
+                ("lemonbar-right", vec!["b"]),
+            ]
+        );
+        assert!(matches!(widgets[0].alignment, Alignment::Left));
+        assert!(widgets[1].alignment.is_center());
+        assert!(widgets[2].alignment.is_right());
+    }
+
+    #[test]
+    fn lemonbar_switches_underlining() {
+        let widgets = lemonbar::parse_line("a%{+u}b%{-u}c%{!u}d%{!u}e");
+        let underlined: Vec<bool> = widgets[0].content.iter().map(|a| a.underline).collect();
+        assert_eq!(underlined, [false, true, false, true, false]);
+    }
+
+    #[test]
+    fn lemonbar_nests_clickable_regions() {
+        let widgets = lemonbar::parse_line(r"%{A:outer\:x:}a%{A3:inner:}b%{A}c%{A}d");
+        let handlers: Vec<Vec<(MouseButton, &str)>> = widgets[0]
+            .content
+            .iter()
+            .map(|a| {
+                let handlers = a.on_click.iter();
+                handlers.map(|h| (h.button, h.output.as_str())).collect()
+            })
+            .collect();
+        let outer = (MouseButton::Left, "outer:x");
+        let inner = (MouseButton::Right, "inner");
+        assert_eq!(
+            handlers,
+            [vec![outer], vec![outer, inner], vec![outer], vec![]]
+        );
+    }
+
+    #[test]
+    fn place_areas_by_alignment() {
+        let areas = [
+            (Alignment::Right, 20.0),
+            (Alignment::Fill, 0.0),
+            (Alignment::Left, 50.0),
+            (Alignment::Center, 60.0),
+            (Alignment::Right, 20.0),
+        ];
+        assert_eq!(
+            placed(&areas),
+            [
+                (260.0, 0.0, 20.0, 20.0),
+                (50.0, 0.0, 210.0, 20.0),
+                (0.0, 0.0, 50.0, 20.0),
+                (120.0, 0.0, 60.0, 20.0),
+                (280.0, 0.0, 20.0, 20.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn place_areas_centres_over_a_long_side() {
+        let areas = [(Alignment::Left, 150.0), (Alignment::Center, 60.0)];
+        assert_eq!(placed(&areas), [(0.0, 0.0, 150.0, 20.0), (120.0, 0.0, 60.0, 20.0)]);
+    }
+
+    #[test]
+    fn empty_areas_leave_no_sliver() {
+        let area = |json| serde_json::from_value::<Area>(json).unwrap();
+        let empty = area(serde_json::json!({}));
+        let spacer = area(serde_json::json!({"colours": {"bg": "#ff0000"}}));
+        assert!(empty.is_empty());
+        assert!(!spacer.is_empty());
+
+        let widgets: [Widget; 1] = [serde_json::from_value(serde_json::json!({
+            "alignment": "left",
+            "content": [{}, {"colours": {"bg": "#ff0000"}}, {}, {"text": "x"}],
+        }))
+        .unwrap()];
+        let output = create_image_output(&Config::builder().build().unwrap(), 300).unwrap();
+        let paints = output.render(&widgets, &[], &Damage::All);
+
+        assert_eq!(paints.len(), 2);
+        assert!(paints[0].area.colours.bg.is_some());
+        assert_eq!(paints[1].area.text, "x");
+        assert!(paints.iter().all(|p| p.right > p.left));
+        assert_eq!(paints[0].right, paints[1].left);
+    }
+
+    /// The colour of the pixel at (`x`, `y`) in an image output's current frame.
+    fn pixel(output: &Output, x: usize, y: usize) -> (u8, u8, u8) {
+        let target = output.ctx.cairo.target();
+        // Copy the frame, as its own surface is shared with the output's context.
+        let mut copy =
+            cairo::ImageSurface::create(cairo::Format::ARgb32, x as i32 + 1, y as i32 + 1).unwrap();
+        {
+            let cr = cairo::Context::new(&copy).unwrap();
+            cr.set_source_surface(&target, 0.0, 0.0).unwrap();
+            cr.paint().unwrap();
+        }
+        let stride = copy.stride() as usize;
+        let data = copy.data().unwrap();
+        let p = &data[y * stride + x * 4..][..4];
+        (p[2], p[1], p[0])
+    }
+
+    #[test]
+    fn partial_redraws_repaint_centred_areas_on_top() {
+        let widget = |tag: &str, alignment: &str, width: f64, bg: &str| {
+            serde_json::from_value::<Widget>(serde_json::json!({
+                "tag": tag,
+                "alignment": alignment,
+                "min_width": width,
+                "content": [{"text": "x", "colours": {"bg": bg}}],
+            }))
+            .unwrap()
+        };
+        let mut widgets = [
+            widget("a", "right", 200.0, "#ff0000"),
+            widget("mid", "center", 100.0, "#0000ff"),
+        ];
+        let output = create_image_output(&Config::builder().build().unwrap(), 300).unwrap();
+        let paints = output.render(&widgets, &[], &Damage::All);
+        assert_eq!(pixel(&output, 115, 1), (0, 0, 255));
+
+        // Left areas stop at the centred group, but right-aligned and fill areas can run under
+        // it, so a partial redraw of "a" must not paint over "mid".
+        widgets[0].content[0].text = "y".to_string();
+        output.render(&widgets, &paints, &Damage::Tags(vec!["a".to_string()]));
+        assert_eq!(pixel(&output, 115, 1), (0, 0, 255));
+        assert_eq!(pixel(&output, 250, 1), (255, 0, 0));
+    }
+
+    #[test]
+    fn colour_rejects_non_ascii_and_signs() {
+        for bad in ["#éa", "#aéb", "#+f+f+f", "#+ff", "#+f+f+f+f"] {
+            assert!(bad.parse::<Colour>().is_err(), "{} parsed", bad);
+        }
+    }
+
+    /// What an output lays its areas out with, drawing onto a small image instead of a window.
+    struct Shaper {
+        ctx: OutputContext,
+        font: FontDescription,
+        cfg: Config,
+    }
+
+    impl Shaper {
+        fn new() -> Self {
+            let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, 300, 20).unwrap();
+            let cfg: Config = serde_json::from_str("{}").unwrap();
+            Shaper {
+                ctx: OutputContext {
+                    cairo: cairo::Context::new(&surface).unwrap(),
+                    window: None,
+                },
+                font: FontDescription::new(&cfg.font_str),
+                cfg,
+            }
+        }
+
+        fn layout(&self, text: &str) -> Layout {
+            let area = Area {
+                text: text.to_string(),
+                ..Area::default()
+            };
+            Layout::new(&self.ctx, &area, &self.font.0, &self.cfg)
+        }
+
+        /// Looks `text` up in `cache`, counting the layouts that had to be built.
+        fn cached(&self, cache: &mut LayoutCache, text: &str, built: &mut usize) -> Layout {
+            let key = LayoutKey {
+                text: text.to_string(),
+                markup: false,
+                bold: false,
+                italic: false,
+                underline: false,
+                direction: None,
+                max_width: None,
+                image: None,
+            };
+            cache.get_or_insert(key, || {
+                *built += 1;
+                self.layout(text)
+            })
+        }
+    }
+
+    #[test]
+    fn layout_cache_reuses_layouts_until_unused() {
+        let shaper = Shaper::new();
+        let mut cache = LayoutCache::default();
+        let mut built = 0;
+
+        shaper.cached(&mut cache, "a", &mut built);
+        shaper.cached(&mut cache, "b", &mut built);
+        cache.prune();
+        assert_eq!(built, 2);
+
+        // The next frame keeps "a" and changes "b" to "c".
+        shaper.cached(&mut cache, "a", &mut built);
+        shaper.cached(&mut cache, "c", &mut built);
+        cache.prune();
+        assert_eq!(built, 3);
+
+        // "b" went unused for a frame, so it was pruned.
+        shaper.cached(&mut cache, "b", &mut built);
+        assert_eq!(built, 4);
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture` to see the timings.
+    #[test]
+    #[ignore]
+    fn layout_cache_speeds_up_static_widgets() {
+        const WIDGETS: usize = 50;
+        const FRAMES: usize = 200;
+
+        let shaper = Shaper::new();
+        let texts: Vec<String> = (0..WIDGETS)
+            .map(|i| format!("widget {}: 12:34:56 · 87% · 1.2 GiB", i))
+            .collect();
+
+        let start = std::time::Instant::now();
+        for _ in 0..FRAMES {
+            for text in &texts {
+                shaper.layout(text).display(&shaper.ctx);
+            }
+        }
+        let uncached = start.elapsed();
+
+        let mut cache = LayoutCache::default();
+        let mut built = 0;
+        let start = std::time::Instant::now();
+        for _ in 0..FRAMES {
+            for text in &texts {
+                shaper.cached(&mut cache, text, &mut built).display(&shaper.ctx);
+            }
+            cache.prune();
+        }
+        let cached = start.elapsed();
+
+        println!(
+            "{} frames of {} widgets: {:?} uncached, {:?} cached",
+            FRAMES, WIDGETS, uncached, cached
+        );
+        assert_eq!(built, WIDGETS);
+        assert!(cached < uncached);
+    }
 }